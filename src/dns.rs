@@ -0,0 +1,203 @@
+/*
+    Host name resolution.
+    Looking a host up every single request is slow, so answers are cached for a while. The system
+    resolver doesn't tell us the record's real TTL, so every entry just lives for the same time.
+
+    Overrides work like curl's `--resolve host:port:address`, they let us point a real host name at
+    a local server while testing without touching /etc/hosts. An override always wins over the cache.
+*/
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// How long a cached lookup is trusted for.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+struct CacheEntry {
+    addresses: Vec<IpAddr>,
+    expires: Instant,
+}
+
+pub struct Resolver {
+    ttl: Duration,
+    cache: HashMap<String, CacheEntry>,
+    /// Keyed by lowercase host and port, a port of None matches any port.
+    overrides: HashMap<(String, Option<u16>), Vec<IpAddr>>,
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Resolver {
+            ttl: DEFAULT_TTL,
+            cache: HashMap::new(),
+            overrides: HashMap::new(),
+        }
+    }
+}
+
+impl Resolver {
+    /// Answer from the overrides or the cache, without going out to the system resolver.
+    pub fn cached(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        let host = normalize(host);
+        let with_port = |addresses: &[IpAddr]| {
+            addresses
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, port))
+                .collect::<Vec<_>>()
+        };
+
+        if let Some(addresses) = self
+            .overrides
+            .get(&(host.clone(), Some(port)))
+            .or_else(|| self.overrides.get(&(host.clone(), None)))
+        {
+            return Some(with_port(addresses));
+        }
+        // Nothing to look up for an IP literal.
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Some(vec![SocketAddr::new(ip, port)]);
+        }
+        self.cache
+            .get(&host)
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| with_port(&entry.addresses))
+    }
+
+    /// Remember the result of a lookup for the TTL.
+    pub fn store(&mut self, host: &str, addresses: &[SocketAddr]) {
+        self.cache.insert(
+            normalize(host),
+            CacheEntry {
+                addresses: addresses.iter().map(SocketAddr::ip).collect(),
+                expires: Instant::now() + self.ttl,
+            },
+        );
+    }
+
+    /// Always send `host` (on `port`, or any port if None) to these addresses instead.
+    pub fn add_override(&mut self, host: &str, port: Option<u16>, addresses: Vec<IpAddr>) {
+        self.overrides
+            .insert((host.to_ascii_lowercase(), port), addresses);
+    }
+
+    /// Add an override from a curl style `host:port:address[,address...]` string. The port can be
+    /// `*` to match any port, and IPv6 addresses can be written in brackets.
+    pub fn add_override_spec(&mut self, spec: &str) -> Result<(), String> {
+        let invalid = || {
+            format!(
+                "Invalid resolve override {}, expected host:port:address",
+                spec
+            )
+        };
+        let mut parts = spec.splitn(3, ':');
+        let (Some(host), Some(port), Some(addresses)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse::<u16>().map_err(|_| invalid())?),
+        };
+        let addresses = addresses
+            .split(',')
+            .map(|address| {
+                address
+                    .trim()
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+                    .map_err(|_| invalid())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.add_override(host, port, addresses);
+        Ok(())
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    /// Forget every cached lookup, overrides stay.
+    pub fn clear_cache(&mut self) {
+        self.cache.clear();
+    }
+}
+
+/// The resolver every request shares.
+pub fn resolver() -> std::sync::MutexGuard<'static, Resolver> {
+    static RESOLVER: OnceLock<Mutex<Resolver>> = OnceLock::new();
+    RESOLVER
+        .get_or_init(|| Mutex::new(Resolver::default()))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Find the addresses to connect to for `host` on `port`. The shared resolver isn't locked during
+/// the actual lookup, so one slow host doesn't hold up requests to every other host.
+pub fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+    if let Some(addresses) = resolver().cached(host, port) {
+        return Ok(addresses);
+    }
+    let host = normalize(host);
+    let addresses: Vec<SocketAddr> = (host.as_str(), port)
+        .to_socket_addrs()
+        .map_err(|e| format!("Failed to resolve host {}: {}", host, e))?
+        .collect();
+    if addresses.is_empty() {
+        return Err(format!("No address found for host: {}", host));
+    }
+    resolver().store(&host, &addresses);
+    Ok(addresses)
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase()
+}
+
+#[test]
+fn test_resolver_overrides_and_cache() {
+    let mut resolver = Resolver::default();
+    resolver
+        .add_override_spec("example.com:443:127.0.0.1,[::1]")
+        .unwrap();
+    resolver
+        .add_override_spec("Example.org:*:10.0.0.1")
+        .unwrap();
+    assert!(
+        resolver
+            .add_override_spec("example.com:https:127.0.0.1")
+            .is_err()
+    );
+
+    assert_eq!(
+        resolver.cached("EXAMPLE.com", 443).unwrap(),
+        vec![
+            "127.0.0.1:443".parse().unwrap(),
+            "[::1]:443".parse().unwrap()
+        ]
+    );
+    assert_eq!(
+        resolver.cached("example.org", 8080).unwrap(),
+        vec!["10.0.0.1:8080".parse().unwrap()]
+    );
+    assert_eq!(resolver.cached("example.com", 80), None);
+
+    // Cached answers are used until they expire.
+    resolver.store("cached.test", &["192.0.2.1:1".parse().unwrap()]);
+    assert_eq!(
+        resolver.cached("cached.test", 80).unwrap(),
+        vec!["192.0.2.1:80".parse().unwrap()]
+    );
+    resolver.set_ttl(Duration::ZERO);
+    resolver.store("cached.test", &["192.0.2.1:1".parse().unwrap()]);
+    assert_eq!(resolver.cached("cached.test", 80), None);
+}
@@ -0,0 +1,377 @@
+/*
+    Downloads that go straight to disk.
+    The body is written out as it arrives into `<name>.part`, with a small `<name>.part.meta` file
+    next to it that remembers the URL and the validator (ETag or Last-Modified) the server gave us.
+    If the download gets interrupted, the next attempt asks for the rest with
+    `Range: bytes=<size>-` and `If-Range: <validator>`:
+    - 206 Partial Content means the file hasn't changed and we append to what we have.
+    - 200 OK means it has changed (or the server ignores ranges), so we start again from scratch.
+    - 416 Range Not Satisfiable usually means we already had all of it.
+    Once everything has arrived the .part file is renamed to its real name.
+*/
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    referrer::ReferrerPolicy,
+    url::{ResponseReader, URL},
+};
+
+/// Redirects we'll follow before giving up.
+const MAX_REDIRECTS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// Bytes on disk so far, including anything from an earlier attempt.
+    pub received: u64,
+    pub total: Option<u64>,
+}
+
+/// What's needed to pick an interrupted download back up.
+#[derive(Debug, Clone, PartialEq)]
+struct Resume {
+    offset: u64,
+    validator: String,
+    /// What the file gets called once it's finished, as the first attempt was told.
+    name: String,
+}
+
+pub struct DownloadManager {
+    directory: PathBuf,
+}
+
+impl DownloadManager {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        DownloadManager {
+            directory: directory.into(),
+        }
+    }
+
+    /// Download `url` into the directory, carrying on from an earlier partial download of it if
+    /// there is one. Returns where the file ended up.
+    pub fn download(
+        &self,
+        url: URL,
+        mut progress: impl FnMut(DownloadProgress),
+    ) -> Result<PathBuf, String> {
+        // The real name can depend on the response, the .part file has to be found before that.
+        let part_name = sanitize_filename(url.path.rsplit('/').next().unwrap_or_default());
+        let part = self.directory.join(format!("{}.part", part_name));
+        let meta = self.directory.join(format!("{}.part.meta", part_name));
+        let address = url.build();
+
+        let mut resume = load_resume(&part, &meta, &address);
+        let mut reader = start(&url, resume.as_ref())?;
+
+        if let Some(offset) = resume.as_ref().map(|r| r.offset) {
+            let total = reader.header("Content-Range").and_then(parse_content_range);
+            match reader.status_code() {
+                // We already had the whole thing, the last attempt just didn't get to finish up.
+                Some(416) if total.is_some_and(|range| range.total == Some(offset)) => {
+                    reader.abandon();
+                    let name = resume.map(|r| r.name).unwrap_or_default();
+                    return finish(&part, &meta, &self.directory, &name);
+                }
+                Some(206) if total.is_some_and(|range| range.start == offset) => {}
+                // Anything else and we can't trust what's on disk.
+                _ => {
+                    resume = None;
+                    if reader.status_code() != Some(200) {
+                        reader.abandon();
+                        reader = start(&url, None)?;
+                    }
+                }
+            }
+        }
+
+        match reader.status_code() {
+            Some(200 | 206) => {}
+            _ => return Err(format!("Download failed: {}", reader.status)),
+        }
+
+        let offset = resume.as_ref().map_or(0, |r| r.offset);
+        let total = match reader.header("Content-Range").and_then(parse_content_range) {
+            Some(range) if resume.is_some() => range.total,
+            _ => reader.content_length().map(|length| length as u64 + offset),
+        };
+
+        let name = match &resume {
+            Some(resume) => resume.name.clone(),
+            None => filename(reader.header("Content-Disposition"), &url),
+        };
+
+        // Remember how to resume this before any of the body is written.
+        match validator(&reader) {
+            Some(validator) => fs::write(&meta, format!("{}\n{}\n{}\n", address, validator, name))
+                .map_err(|e| format!("Failed to write {}: {}", meta.display(), e))?,
+            None => {
+                let _ = fs::remove_file(&meta);
+            }
+        }
+
+        let mut file = if resume.is_some() {
+            OpenOptions::new().append(true).open(&part)
+        } else {
+            File::create(&part)
+        }
+        .map_err(|e| format!("Failed to open {}: {}", part.display(), e))?;
+
+        let mut received = offset;
+        progress(DownloadProgress { received, total });
+        while let Some(chunk) = reader.read_chunk()? {
+            file.write_all(&chunk)
+                .map_err(|e| format!("Failed to write {}: {}", part.display(), e))?;
+            received += chunk.len() as u64;
+            progress(DownloadProgress { received, total });
+        }
+        file.flush()
+            .map_err(|e| format!("Failed to write {}: {}", part.display(), e))?;
+
+        finish(&part, &meta, &self.directory, &name)
+    }
+}
+
+/// Send the request, with a Range if we're resuming, following any redirects.
+fn start(url: &URL, resume: Option<&Resume>) -> Result<ResponseReader, String> {
+    let mut url = url.clone();
+    for _ in 0..=MAX_REDIRECTS {
+        // Downloads are meant for big files, so the page size limit doesn't apply.
+        url.max_body_size = usize::MAX;
+        let mut request = url.clone();
+        if let Some(resume) = resume {
+            request
+                .headers
+                .insert("Range".to_string(), format!("bytes={}-", resume.offset));
+            request
+                .headers
+                .insert("If-Range".to_string(), resume.validator.clone());
+        }
+
+        let reader = request.open()?;
+        match (reader.status_code(), reader.header("Location")) {
+            (Some(300..=399), Some(location)) => {
+                if let Some(policy) = reader
+                    .header("Referrer-Policy")
+                    .and_then(ReferrerPolicy::from_header)
+                {
+                    url.referrer_policy = policy;
+                }
+                url = url.redirect_to(location)?
+            }
+            _ => return Ok(reader),
+        }
+    }
+    Err("Too many redirects".to_string())
+}
+
+/// Move the finished .part file to its real name, without overwriting anything already there.
+fn finish(part: &Path, meta: &Path, directory: &Path, name: &str) -> Result<PathBuf, String> {
+    let mut path = directory.join(name);
+    let mut n = 1;
+    while path.exists() {
+        path = directory.join(format!("{} ({})", name, n));
+        n += 1;
+    }
+    fs::rename(part, &path).map_err(|e| format!("Failed to save {}: {}", path.display(), e))?;
+    let _ = fs::remove_file(meta);
+    Ok(path)
+}
+
+/// A partial download can only be resumed if it's for the same URL and we have a validator for it.
+fn load_resume(part: &Path, meta: &Path, address: &str) -> Option<Resume> {
+    let contents = fs::read_to_string(meta).ok()?;
+    let mut lines = contents.lines();
+    if lines.next()? != address {
+        return None;
+    }
+    let validator = lines.next()?.to_string();
+    let name = lines.next()?.to_string();
+    let offset = fs::metadata(part).ok()?.len();
+    (offset > 0).then_some(Resume {
+        offset,
+        validator,
+        name,
+    })
+}
+
+/// If-Range needs a strong validator, so weak ETags don't count.
+fn validator(reader: &ResponseReader) -> Option<String> {
+    reader
+        .header("ETag")
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| reader.header("Last-Modified"))
+        .map(str::to_string)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub start: u64,
+    pub end: u64,
+    pub total: Option<u64>,
+}
+
+/// Parse `bytes 100-199/1000`, `bytes 100-199/*` or the `bytes */1000` a 416 comes with (which we
+/// return with start and end both 0).
+pub fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let value = value.trim().strip_prefix("bytes")?.trim_start();
+    let (range, total) = value.split_once('/')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    if range.trim() == "*" {
+        return Some(ContentRange {
+            start: 0,
+            end: 0,
+            total,
+        });
+    }
+    let (start, end) = range.split_once('-')?;
+    Some(ContentRange {
+        start: start.trim().parse().ok()?,
+        end: end.trim().parse().ok()?,
+        total,
+    })
+}
+
+/// What to save a download as: the Content-Disposition filename if there is one, otherwise the
+/// last bit of the path.
+pub fn filename(content_disposition: Option<&str>, url: &URL) -> String {
+    let from_header = content_disposition.and_then(|value| {
+        value.split(';').find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            (key.trim().eq_ignore_ascii_case("filename"))
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+    });
+    let name = from_header
+        .or_else(|| url.path.rsplit('/').next().map(str::to_string))
+        .unwrap_or_default();
+    sanitize_filename(&name)
+}
+
+/// Only keep the file name itself, a server shouldn't get to pick the directory.
+fn sanitize_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    if name.is_empty() || name == "." || name == ".." {
+        "download".to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[test]
+fn test_parse_content_range() {
+    assert_eq!(
+        parse_content_range("bytes 100-199/1000"),
+        Some(ContentRange {
+            start: 100,
+            end: 199,
+            total: Some(1000)
+        })
+    );
+    assert_eq!(
+        parse_content_range("bytes */1000").unwrap().total,
+        Some(1000)
+    );
+    assert_eq!(parse_content_range("bytes 0-1/*").unwrap().total, None);
+    assert_eq!(parse_content_range("items 0-1/2"), None);
+}
+
+#[test]
+fn test_download_filename() {
+    let url = URL::from_string("http://example.com/files/get?id=1").unwrap();
+    assert_eq!(filename(None, &url), "get");
+    let disposition = "attachment; filename=\"../report.pdf\"";
+    assert_eq!(filename(Some(disposition), &url), "report.pdf");
+}
+
+#[test]
+fn test_resume_download() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // A tiny server for one file that understands Range and If-Range.
+    const FILE: &[u8] = b"0123456789";
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let mut request = Vec::new();
+            let mut reader = BufReader::new(&stream);
+            let mut line = String::new();
+            while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                request.push(line.trim().to_string());
+                line.clear();
+            }
+            let header = |name: &str| {
+                request
+                    .iter()
+                    .find_map(|l| l.strip_prefix(&format!("{}: ", name)).map(str::to_string))
+            };
+            let start = header("Range")
+                .filter(|_| header("If-Range").as_deref() == Some("\"v1\""))
+                .and_then(|r| r.strip_prefix("bytes=")?.strip_suffix('-')?.parse().ok());
+            let response = match start {
+                Some(start) => format!(
+                    "HTTP/1.1 206 Partial Content\r\nETag: \"v1\"\r\nContent-Range: bytes {}-9/10\r\nContent-Length: {}\r\n\r\n",
+                    start,
+                    10 - start
+                )
+                .into_bytes()
+                .into_iter()
+                .chain(FILE[start..].iter().copied())
+                .collect::<Vec<u8>>(),
+                None => [
+                    b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 10\r\n\r\n".as_slice(),
+                    FILE,
+                ]
+                .concat(),
+            };
+            let _ = (&stream).write_all(&response);
+        }
+    });
+
+    let directory = std::env::temp_dir().join(format!("download-test-{}", port));
+    fs::create_dir_all(&directory).unwrap();
+    let url = URL::from_string(format!("http://127.0.0.1:{}/file.txt", port)).unwrap();
+
+    // Pretend an earlier attempt got the first four bytes.
+    fs::write(directory.join("file.txt.part"), b"0123").unwrap();
+    fs::write(
+        directory.join("file.txt.part.meta"),
+        format!("{}\n\"v1\"\nfile.txt\n", url.build()),
+    )
+    .unwrap();
+
+    let mut seen = Vec::new();
+    let manager = DownloadManager::new(&directory);
+    let path = manager
+        .download(url.clone(), |progress| seen.push(progress))
+        .unwrap();
+    assert_eq!(fs::read(&path).unwrap(), FILE);
+    assert_eq!(
+        seen.first(),
+        Some(&DownloadProgress {
+            received: 4,
+            total: Some(10)
+        })
+    );
+    assert!(!directory.join("file.txt.part.meta").exists());
+
+    // A stale validator means starting over.
+    fs::write(directory.join("file.txt.part"), b"abc").unwrap();
+    fs::write(
+        directory.join("file.txt.part.meta"),
+        format!("{}\n\"v0\"\nfile.txt\n", url.build()),
+    )
+    .unwrap();
+    let path = manager.download(url, |_| {}).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), FILE);
+
+    let _ = fs::remove_dir_all(&directory);
+}
@@ -0,0 +1,454 @@
+/*
+    Character encoding detection and decoding.
+    This loosely follows https://encoding.spec.whatwg.org/ and the "determining the character encoding"
+    steps from https://html.spec.whatwg.org/multipage/parsing.html#encoding-sniffing-algorithm.
+
+    The order we go through is:
+    1. A byte order mark at the start of the body.
+    2. The charset parameter of the Content-Type header.
+    3. A <meta charset> or <meta http-equiv="Content-Type"> in the first 1024 bytes.
+    4. Fall back to UTF-8 if the bytes are valid UTF-8, otherwise windows-1252.
+
+    Only a handful of encodings are supported, which covers most of the older sites we care about.
+*/
+
+/// How many bytes of the body the meta prescan looks at.
+const PRESCAN_LIMIT: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+    Iso8859_15,
+}
+
+impl Encoding {
+    /// Look up an encoding from a label such as "UTF-8", "latin1" or "cp1252".
+    /// Note that like every browser we treat iso-8859-1 and us-ascii as windows-1252.
+    pub fn from_label(label: &str) -> Option<Self> {
+        let label = label
+            .trim_matches(|c: char| c.is_ascii_whitespace() || c == '"' || c == '\'')
+            .to_ascii_lowercase();
+        match label.as_str() {
+            "unicode-1-1-utf-8" | "unicode11utf8" | "unicode20utf8" | "utf-8" | "utf8"
+            | "x-unicode20utf8" => Some(Encoding::Utf8),
+            "csunicode" | "iso-10646-ucs-2" | "ucs-2" | "unicode" | "unicodefeff" | "utf-16"
+            | "utf-16le" => Some(Encoding::Utf16Le),
+            "unicodefffe" | "utf-16be" => Some(Encoding::Utf16Be),
+            "ansi_x3.4-1968" | "ascii" | "cp1252" | "cp819" | "csisolatin1" | "ibm819"
+            | "iso-8859-1" | "iso-ir-100" | "iso8859-1" | "iso88591" | "iso_8859-1"
+            | "iso_8859-1:1987" | "l1" | "latin1" | "us-ascii" | "windows-1252" | "x-cp1252" => {
+                Some(Encoding::Windows1252)
+            }
+            "csisolatin9" | "iso-8859-15" | "iso8859-15" | "iso885915" | "iso_8859-15" | "l9" => {
+                Some(Encoding::Iso8859_15)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Windows1252 => "windows-1252",
+            Encoding::Iso8859_15 => "ISO-8859-15",
+        }
+    }
+
+    /// Decode the bytes into a string, invalid sequences become U+FFFD.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).to_string(),
+            Encoding::Utf16Le => decode_utf16(bytes, u16::from_le_bytes),
+            Encoding::Utf16Be => decode_utf16(bytes, u16::from_be_bytes),
+            Encoding::Windows1252 => bytes.iter().map(|&b| windows_1252_char(b)).collect(),
+            Encoding::Iso8859_15 => bytes.iter().map(|&b| iso_8859_15_char(b)).collect(),
+        }
+    }
+}
+
+/// Decode a response body, returning the text and the encoding that was used.
+/// `content_type` is the raw value of the Content-Type header if there was one.
+pub fn decode(bytes: &[u8], content_type: Option<&str>) -> (String, Encoding) {
+    let (encoding, bom_len) = detect(bytes, content_type);
+    (encoding.decode(&bytes[bom_len..]), encoding)
+}
+
+/// The encoding `decode` would use for the whole body.
+pub fn detect_encoding(bytes: &[u8], content_type: Option<&str>) -> Encoding {
+    detect(bytes, content_type).0
+}
+
+/// Work out the encoding in the order above, along with the length of the BOM if there is one.
+fn detect(bytes: &[u8], content_type: Option<&str>) -> (Encoding, usize) {
+    // A BOM wins over everything else and is not part of the text.
+    if let Some(bom) = sniff_bom(bytes) {
+        return bom;
+    }
+
+    let encoding = content_type
+        .and_then(charset_from_content_type)
+        .or_else(|| prescan(bytes))
+        .unwrap_or_else(|| match std::str::from_utf8(bytes) {
+            Ok(_) => Encoding::Utf8,
+            // Cut off in the middle of a character is still UTF-8, that happens to the start of
+            // a body that's still arriving.
+            Err(error) if error.error_len().is_none() => Encoding::Utf8,
+            Err(_) => Encoding::Windows1252,
+        });
+    (encoding, 0)
+}
+
+/// Decodes a body that arrives a chunk at a time. Nothing comes out until there's enough of the
+/// start to pick the encoding from, and a character split between chunks waits for the rest.
+/// With no BOM or charset to go on, only the first 1024 bytes get checked for being UTF-8, so
+/// once the whole body is there check it against `detect_encoding` in case it needs decoding again.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    content_type: Option<String>,
+    encoding: Option<Encoding>,
+    /// Bytes that haven't been decoded yet.
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(content_type: Option<&str>) -> Self {
+        Decoder {
+            content_type: content_type.map(str::to_string),
+            encoding: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// None until it's been decided.
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    /// The text for the next chunk of the body, which might be less than all of it.
+    pub fn decode(&mut self, chunk: &[u8]) -> String {
+        self.pending.extend_from_slice(chunk);
+        if self.encoding.is_none() {
+            // Unless the header says, the meta prescan needs to see all of its 1024 bytes. A BOM
+            // still beats the header, so wait long enough to be sure there isn't one.
+            let declared = self
+                .content_type
+                .as_deref()
+                .and_then(charset_from_content_type)
+                .is_some();
+            if self.pending.len() < PRESCAN_LIMIT && !(declared && self.pending.len() >= 3) {
+                return String::new();
+            }
+            self.detect();
+        }
+        let complete = self.complete_len();
+        self.take(complete)
+    }
+
+    /// The body is over, decode whatever's left.
+    pub fn finish(&mut self) -> String {
+        if self.encoding.is_none() {
+            self.detect();
+        }
+        self.take(self.pending.len())
+    }
+
+    fn detect(&mut self) {
+        let (encoding, bom_len) = detect(&self.pending, self.content_type.as_deref());
+        self.pending.drain(..bom_len);
+        self.encoding = Some(encoding);
+    }
+
+    /// Decode and drop the first `len` pending bytes.
+    fn take(&mut self, len: usize) -> String {
+        let encoding = self.encoding.unwrap_or(Encoding::Utf8);
+        let text = encoding.decode(&self.pending[..len]);
+        self.pending.drain(..len);
+        text
+    }
+
+    /// How many of the pending bytes end on a character boundary.
+    fn complete_len(&self) -> usize {
+        let bytes = &self.pending;
+        match self.encoding {
+            Some(Encoding::Utf8) => {
+                // Find the start of the last character and check all of it is there.
+                for back in 1..=bytes.len().min(4) {
+                    let b = bytes[bytes.len() - back];
+                    if b & 0xC0 != 0x80 {
+                        let width = match b {
+                            0xC0..=0xDF => 2,
+                            0xE0..=0xEF => 3,
+                            0xF0..=0xF7 => 4,
+                            _ => 1,
+                        };
+                        return if width > back {
+                            bytes.len() - back
+                        } else {
+                            bytes.len()
+                        };
+                    }
+                }
+                bytes.len()
+            }
+            Some(encoding @ (Encoding::Utf16Le | Encoding::Utf16Be)) => {
+                let even = bytes.len() & !1;
+                if even < 2 {
+                    return even;
+                }
+                let pair = [bytes[even - 2], bytes[even - 1]];
+                let unit = match encoding {
+                    Encoding::Utf16Le => u16::from_le_bytes(pair),
+                    _ => u16::from_be_bytes(pair),
+                };
+                // The first half of a surrogate pair waits for its second.
+                if (0xD800..0xDC00).contains(&unit) {
+                    even - 2
+                } else {
+                    even
+                }
+            }
+            _ => bytes.len(),
+        }
+    }
+}
+
+/// Check for a UTF-8 or UTF-16 byte order mark, returning the encoding and the length of the BOM.
+pub fn sniff_bom(bytes: &[u8]) -> Option<(Encoding, usize)> {
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF]) {
+        Some((Encoding::Utf8, 3))
+    } else if bytes.starts_with(&[0xFE, 0xFF]) {
+        Some((Encoding::Utf16Be, 2))
+    } else if bytes.starts_with(&[0xFF, 0xFE]) {
+        Some((Encoding::Utf16Le, 2))
+    } else {
+        None
+    }
+}
+
+/// Pull the charset parameter out of a header value like `text/html; charset="ISO-8859-1"`.
+pub fn charset_from_content_type(value: &str) -> Option<Encoding> {
+    value.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("charset") {
+            Encoding::from_label(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// A simplified version of the spec's meta prescan. We look through the first 1024 bytes for
+/// `<meta charset=...>` or `<meta http-equiv="Content-Type" content="...; charset=...">`,
+/// skipping over comments.
+pub fn prescan(bytes: &[u8]) -> Option<Encoding> {
+    // Everything we care about is ASCII, so a lossy latin1 view of the bytes is fine here.
+    let head: String = bytes
+        .iter()
+        .take(PRESCAN_LIMIT)
+        .map(|&b| b as char)
+        .collect();
+    let lower = head.to_ascii_lowercase();
+
+    let mut pos = 0;
+    while let Some(offset) = lower[pos..].find('<') {
+        let start = pos + offset;
+        if lower[start..].starts_with("<!--") {
+            pos = match lower[start + 4..].find("-->") {
+                Some(end) => start + 4 + end + 3,
+                None => return None,
+            };
+            continue;
+        }
+        let end = match lower[start..].find('>') {
+            Some(end) => start + end,
+            None => return None,
+        };
+        let tag = &head[start + 1..end];
+        pos = end + 1;
+
+        let is_meta = tag.get(..4).is_some_and(|t| t.eq_ignore_ascii_case("meta"))
+            && tag[4..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/');
+        if !is_meta {
+            continue;
+        }
+
+        let attributes = meta_attributes(&tag[4..]);
+        let get = |name: &str| {
+            attributes
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let encoding = if let Some(charset) = get("charset") {
+            Encoding::from_label(charset)
+        } else if get("http-equiv").is_some_and(|v| v.eq_ignore_ascii_case("content-type")) {
+            get("content").and_then(charset_from_content_type)
+        } else {
+            None
+        };
+
+        if let Some(encoding) = encoding {
+            // The spec says a meta can't switch us to UTF-16, since we've clearly read it as ASCII.
+            return Some(match encoding {
+                Encoding::Utf16Le | Encoding::Utf16Be => Encoding::Utf8,
+                other => other,
+            });
+        }
+    }
+    None
+}
+
+/// Very small attribute splitter used by the prescan, names are lowercased.
+fn meta_attributes(s: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        while chars
+            .peek()
+            .is_some_and(|c| c.is_ascii_whitespace() || *c == '/')
+        {
+            chars.next();
+        }
+        let mut name = String::new();
+        while let Some(&c) = chars.peek() {
+            if c.is_ascii_whitespace() || c == '=' || c == '/' {
+                break;
+            }
+            name.push(c.to_ascii_lowercase());
+            chars.next();
+        }
+        if name.is_empty() {
+            break;
+        }
+        while chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+            chars.next();
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            while chars.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                chars.next();
+            }
+            match chars.peek() {
+                Some(&quote) if quote == '"' || quote == '\'' => {
+                    chars.next();
+                    for c in chars.by_ref() {
+                        if c == quote {
+                            break;
+                        }
+                        value.push(c);
+                    }
+                }
+                _ => {
+                    while let Some(&c) = chars.peek() {
+                        if c.is_ascii_whitespace() {
+                            break;
+                        }
+                        value.push(c);
+                        chars.next();
+                    }
+                }
+            }
+        }
+        attributes.push((name, value));
+    }
+    attributes
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> String {
+    let units = bytes
+        .chunks_exact(2)
+        .map(|pair| to_unit([pair[0], pair[1]]));
+    let mut text: String = char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+    // A trailing odd byte can't form a code unit.
+    if bytes.len() % 2 == 1 {
+        text.push(char::REPLACEMENT_CHARACTER);
+    }
+    text
+}
+
+/// windows-1252 is latin1 apart from 0x80-0x9F which hold curly quotes, dashes, the euro sign etc.
+pub(crate) fn windows_1252_char(b: u8) -> char {
+    const HIGH: [u16; 32] = [
+        0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021, 0x02C6, 0x2030, 0x0160,
+        0x2039, 0x0152, 0x008D, 0x017D, 0x008F, 0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022,
+        0x2013, 0x2014, 0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178,
+    ];
+    match b {
+        0x80..=0x9F => char::from_u32(HIGH[(b - 0x80) as usize] as u32).unwrap_or('\u{FFFD}'),
+        _ => b as char,
+    }
+}
+
+/// ISO-8859-15 swaps eight latin1 characters for the euro sign and a few letters.
+fn iso_8859_15_char(b: u8) -> char {
+    match b {
+        0xA4 => '\u{20AC}',
+        0xA6 => '\u{0160}',
+        0xA8 => '\u{0161}',
+        0xB4 => '\u{017D}',
+        0xB8 => '\u{017E}',
+        0xBC => '\u{0152}',
+        0xBD => '\u{0153}',
+        0xBE => '\u{0178}',
+        _ => b as char,
+    }
+}
+
+#[test]
+fn test_decode_sources() {
+    // BOM beats the header.
+    let (text, encoding) = decode(
+        b"\xEF\xBB\xBFcaf\xC3\xA9",
+        Some("text/html; charset=latin1"),
+    );
+    assert_eq!((text.as_str(), encoding), ("café", Encoding::Utf8));
+
+    // Header charset.
+    let (text, _) = decode(
+        b"caf\xE9 \x93hi\x94",
+        Some("text/html; charset=\"ISO-8859-1\""),
+    );
+    assert_eq!(text, "café \u{201C}hi\u{201D}");
+
+    // Meta declarations, both forms.
+    let html = b"<!-- <meta charset=utf-8> --><meta charset='windows-1252'><p>\xE9";
+    assert_eq!(decode(html, Some("text/html")).1, Encoding::Windows1252);
+    let html = b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-15\">\xA4";
+    assert_eq!(decode(html, None).0.chars().last(), Some('\u{20AC}'));
+
+    // No declaration at all.
+    assert_eq!(decode("naïve".as_bytes(), None).1, Encoding::Utf8);
+    assert_eq!(decode(b"na\xEFve", None).1, Encoding::Windows1252);
+}
+
+#[test]
+fn test_decoder_chunks() {
+    // Split in the middle of characters, the text comes out the same as decoding it all at once.
+    let body = "<meta charset=utf-8><p>caf\u{e9} \u{1F600}".repeat(40);
+    let mut decoder = Decoder::new(Some("text/html"));
+    let mut text = String::new();
+    for chunk in body.as_bytes().chunks(7) {
+        text.push_str(&decoder.decode(chunk));
+    }
+    text.push_str(&decoder.finish());
+    assert_eq!(text, body);
+    assert_eq!(decoder.encoding(), Some(Encoding::Utf8));
+
+    let mut decoder = Decoder::new(None);
+    let bytes = [0xFF, 0xFE, 0x3D, 0xD8, 0x00, 0xDE, b'!', 0];
+    let text: String = bytes.chunks(3).map(|chunk| decoder.decode(chunk)).collect();
+    assert_eq!(text, "");
+    assert_eq!(decoder.finish(), "\u{1F600}!");
+
+    // With the charset in the header there's no need to wait for the prescan.
+    let mut decoder = Decoder::new(Some("text/html; charset=windows-1252"));
+    assert_eq!(decoder.decode(b"caf\xE9"), "caf\u{e9}");
+}
@@ -0,0 +1,308 @@
+/*
+    Background fetching.
+    Requests run on a small pool of worker threads so the window can open straight away and the
+    event loop never sits waiting on the network. Every fetch gets an id, and its progress is sent
+    back to whoever owns the service (the renderer) through the callback it was created with.
+
+    Documents, stylesheets and images all go through the same queue, so subresources get fetched
+    in parallel with each other. An HTML document is parsed as it downloads too, and what's there
+    so far is laid out and sent back every so often so a long page can be shown before it's all
+    arrived.
+
+    Whatever can be done without the window happens here as well: pages are parsed and laid out
+    and images decoded, so the event loop only has to swap the result in.
+*/
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::html::dom::Document;
+use crate::layout::text::{Body, DecodedImage};
+use crate::url::{self, Response, URL};
+
+/// How many requests can be in flight at once.
+pub const DEFAULT_WORKERS: usize = 4;
+
+/// How often a page that's still downloading gets laid out again and sent back.
+const PARTIAL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Laying out a partial page gets slower as it grows, so after each one we wait at least this many
+/// times as long as it took, which keeps it to a small part of the download however long it is.
+const PARTIAL_BACKOFF: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FetchId(u64);
+
+/// What the fetched resource is for, so the receiver knows what to do with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Document,
+    Style,
+    Image,
+}
+
+#[derive(Debug)]
+pub enum FetchEvent {
+    Started {
+        id: FetchId,
+        destination: Destination,
+        url: String,
+    },
+    /// Some more of the body arrived. `total` is only known if the server sent a Content-Length.
+    Progress {
+        id: FetchId,
+        received: usize,
+        total: Option<usize>,
+    },
+    /// A document that's still downloading, laid out as far as it's been parsed.
+    Partial { id: FetchId, body: Box<Body> },
+    /// What was fetched, or why it failed.
+    Finished {
+        id: FetchId,
+        destination: Destination,
+        result: Result<Box<Fetched>, String>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Fetched {
+    /// The final URL, after redirects.
+    pub url: URL,
+    pub response: Response,
+    pub content: Content,
+}
+
+/// What a response turned into, depending on what it was fetched for.
+#[derive(Debug)]
+pub enum Content {
+    /// An HTML page: its tree (to lay out again as images arrive), laid out as it is now, and the
+    /// stylesheets and images it wants, see `Response::subresources`.
+    Page {
+        document: Box<Document>,
+        body: Body,
+        subresources: Vec<(Destination, String, URL)>,
+    },
+    /// Any other document, or None if we can't show it and it was offered as a download.
+    Other(Option<Body>),
+    Image(DecodedImage),
+    /// There's no CSS yet, so stylesheets are only fetched.
+    Style,
+}
+
+struct Job {
+    id: FetchId,
+    destination: Destination,
+    url: URL,
+}
+
+type Notify = Arc<dyn Fn(FetchEvent) + Send + Sync>;
+
+pub struct FetchService {
+    jobs: Option<Sender<Job>>,
+    next_id: AtomicU64,
+}
+
+impl FetchService {
+    /// Start `threads` workers. `notify` is called from the worker threads for every event, so it
+    /// should just hand the event over to the UI thread (e.g. through an event loop proxy).
+    pub fn new(threads: usize, notify: impl Fn(FetchEvent) + Send + Sync + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let notify: Notify = Arc::new(notify);
+
+        for n in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let notify = Arc::clone(&notify);
+            thread::Builder::new()
+                .name(format!("fetch-{}", n))
+                .spawn(move || worker(receiver, notify))
+                .expect("Failed to spawn fetch worker");
+        }
+
+        FetchService {
+            jobs: Some(sender),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue a fetch, the result arrives later as a `FetchEvent::Finished` with the returned id.
+    pub fn fetch(&self, url: URL, destination: Destination) -> FetchId {
+        let id = FetchId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        if let Some(jobs) = &self.jobs {
+            // The workers only go away once we drop the sender, so this can't fail.
+            let _ = jobs.send(Job {
+                id,
+                destination,
+                url,
+            });
+        }
+        id
+    }
+}
+
+impl Drop for FetchService {
+    fn drop(&mut self) {
+        // Closing the queue lets idle workers finish. We don't join them, one could be stuck
+        // waiting on a slow server and there's no reason to hold up closing the window for it.
+        self.jobs.take();
+    }
+}
+
+fn worker(jobs: Arc<Mutex<Receiver<Job>>>, notify: Notify) {
+    loop {
+        // Only hold the lock while waiting for the next job, not while fetching it.
+        let job = match jobs.lock() {
+            Ok(jobs) => jobs.recv(),
+            Err(_) => return,
+        };
+        let Ok(job) = job else {
+            return;
+        };
+
+        notify(FetchEvent::Started {
+            id: job.id,
+            destination: job.destination,
+            url: job.url.build(),
+        });
+        let result = load(&job, &notify).map(Box::new);
+        notify(FetchEvent::Finished {
+            id: job.id,
+            destination: job.destination,
+            result,
+        });
+    }
+}
+
+/// Fetch the job's URL and turn it into whatever it's for.
+fn load(job: &Job, notify: &Notify) -> Result<Fetched, String> {
+    let (url, reader) = url::open_following_redirects(job.url.clone())?;
+    let mut parser = match job.destination {
+        Destination::Document => reader.html_parser(&url),
+        _ => None,
+    };
+    let mut next_partial = Instant::now();
+    let response = reader
+        .into_response_with_chunks(|chunk, received, total| {
+            notify(FetchEvent::Progress {
+                id: job.id,
+                received,
+                total,
+            });
+            let Some(parser) = &mut parser else {
+                return;
+            };
+            let changed = !parser.feed(chunk).is_empty();
+            if changed && Instant::now() >= next_partial {
+                let started = Instant::now();
+                let body =
+                    parser.with_document(|document| Body::from_document(document, &HashMap::new()));
+                next_partial =
+                    Instant::now() + PARTIAL_INTERVAL.max(started.elapsed() * PARTIAL_BACKOFF);
+                notify(FetchEvent::Partial {
+                    id: job.id,
+                    body: Box::new(body),
+                });
+            }
+        })
+        .map_err(|e| format!("Failed to load URL: {}", e))?;
+
+    let content = match job.destination {
+        Destination::Document => match response.html_document(&url, parser) {
+            Some(document) => Content::Page {
+                subresources: response.subresources(&url, &document),
+                body: Body::from_document(&document, &HashMap::new()),
+                document: Box::new(document),
+            },
+            None => Content::Other(response.document(&url)),
+        },
+        Destination::Image => Content::Image(DecodedImage::decode(&response.content)?),
+        Destination::Style => Content::Style,
+    };
+    Ok(Fetched {
+        url,
+        response,
+        content,
+    })
+}
+
+#[test]
+fn test_fetch_service_runs_in_parallel() {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let service = FetchService::new(DEFAULT_WORKERS, move |event| {
+        let _ = sender.lock().unwrap().send(event);
+    });
+
+    let ids: Vec<FetchId> = (0..3)
+        .map(|n| {
+            let url = URL::from_string(format!("data:text/plain,{}", n)).unwrap();
+            service.fetch(url, Destination::Document)
+        })
+        .collect();
+
+    let mut finished = Vec::new();
+    while finished.len() < ids.len() {
+        if let FetchEvent::Finished { id, result, .. } = receiver.recv().unwrap() {
+            assert!(result.is_ok());
+            finished.push(id);
+        }
+    }
+    for id in ids {
+        assert!(finished.contains(&id));
+    }
+}
+
+#[test]
+fn test_fetch_service_sends_partial_documents() {
+    let (sender, receiver) = mpsc::channel();
+    let sender = Mutex::new(sender);
+    let service = FetchService::new(1, move |event| {
+        let _ = sender.lock().unwrap().send(event);
+    });
+    // With a charset and long enough that the decoder and tokenizer don't wait for more.
+    let html = format!(
+        "<title>Hi</title><p>Hello</p><img src=http://example.com/a.png><p>{}",
+        "more ".repeat(50)
+    );
+    let url = URL::from_string(format!("data:text/html;charset=utf-8,{}", html)).unwrap();
+    let page = service.fetch(url, Destination::Document);
+    let url = URL::from_string("data:text/html,<p>Not a page".to_string()).unwrap();
+    let image = service.fetch(url, Destination::Image);
+
+    // The first piece of a page is parsed straight away, before the page is finished.
+    let mut partials = Vec::new();
+    let mut finished = HashMap::new();
+    while finished.len() < 2 {
+        match receiver.recv().unwrap() {
+            FetchEvent::Partial { id, body } => partials.push((id, body)),
+            FetchEvent::Finished { id, result, .. } => {
+                finished.insert(id, result);
+            }
+            _ => {}
+        }
+    }
+    assert_eq!(partials.len(), 1);
+    let (id, body) = &partials[0];
+    assert_eq!(*id, page);
+    assert!(body.tokens().iter().any(|token| matches!(
+        token,
+        crate::layout::text::TokenAction::Text(text) if text.text == "Hello"
+    )));
+
+    // The finished page comes laid out along with what it needs, and images come decoded.
+    match &finished[&page].as_ref().unwrap().content {
+        Content::Page { subresources, .. } => {
+            assert_eq!(subresources[0].1, "http://example.com/a.png")
+        }
+        content => panic!("Expected a page, got {:?}", content),
+    }
+    assert!(finished[&image].is_err());
+}
@@ -0,0 +1,259 @@
+/*
+    HTTP Strict Transport Security, https://www.rfc-editor.org/rfc/rfc6797.
+    Once a host has sent us `Strict-Transport-Security` over HTTPS we remember it, and any http://
+    URL for that host (or its subdomains with includeSubDomains) gets upgraded to https:// before
+    we ever send a request.
+
+    The list is saved to disk so it survives restarts. It lives in ~/.browser-engineering/hsts unless
+    the BROWSER_HSTS_FILE environment variable points somewhere else, one host per line:
+    `host expiry-unix-seconds include-subdomains(0/1)`. Tests keep theirs in memory.
+*/
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The directives we care about from a `Strict-Transport-Security` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StsPolicy {
+    pub max_age: u64,
+    pub include_subdomains: bool,
+}
+
+impl StsPolicy {
+    /// Parse a header value like `max-age=31536000; includeSubDomains`. Following the RFC the whole
+    /// header is ignored if max-age is missing or any directive appears twice.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut max_age = None;
+        let mut include_subdomains = false;
+        for directive in value.split(';') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            if name.eq_ignore_ascii_case("max-age") {
+                if max_age.is_some() {
+                    return None;
+                }
+                max_age = Some(argument?.parse::<u64>().ok()?);
+            } else if name.eq_ignore_ascii_case("includeSubDomains") {
+                if include_subdomains {
+                    return None;
+                }
+                include_subdomains = true;
+            }
+        }
+        Some(StsPolicy {
+            max_age: max_age?,
+            include_subdomains,
+        })
+    }
+}
+
+/// Every response from an HSTS host pushes its expiry along a little. That's only worth saving
+/// once it's moved this far, so the file isn't rewritten on every request.
+const EXPIRY_SLACK: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    /// Unix time in seconds.
+    expires: u64,
+    include_subdomains: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct HstsStore {
+    entries: HashMap<String, Entry>,
+    /// Where to save to, None keeps everything in memory.
+    path: Option<PathBuf>,
+}
+
+impl HstsStore {
+    /// Load the list saved at `path`, a missing file is just an empty list.
+    pub fn load(path: PathBuf) -> Self {
+        let entries = std::fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.split_whitespace();
+                let host = fields.next()?.to_string();
+                let expires = fields.next()?.parse().ok()?;
+                let include_subdomains = fields.next()? == "1";
+                Some((
+                    host,
+                    Entry {
+                        expires,
+                        include_subdomains,
+                    },
+                ))
+            })
+            .collect();
+        HstsStore {
+            entries,
+            path: Some(path),
+        }
+    }
+
+    /// Handle a `Strict-Transport-Security` header that arrived over HTTPS from `host`.
+    pub fn observe(&mut self, host: &str, header: &str) {
+        let host = host.to_ascii_lowercase();
+        // IP addresses can't be known HSTS hosts.
+        if host.parse::<IpAddr>().is_ok() || host.starts_with('[') {
+            return;
+        }
+        let Some(policy) = StsPolicy::parse(header) else {
+            return;
+        };
+
+        let changed = if policy.max_age == 0 {
+            self.entries.remove(&host).is_some()
+        } else {
+            let entry = Entry {
+                expires: now().saturating_add(policy.max_age),
+                include_subdomains: policy.include_subdomains,
+            };
+            let old = self.entries.insert(host, entry);
+            old.is_none_or(|old| {
+                old.include_subdomains != entry.include_subdomains
+                    || old.expires.abs_diff(entry.expires) > EXPIRY_SLACK
+            })
+        };
+        if changed {
+            self.save();
+        }
+    }
+
+    /// Whether requests to `host` have to go over HTTPS.
+    pub fn should_upgrade(&self, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let now = now();
+        let live = |name: &str| self.entries.get(name).filter(|entry| entry.expires > now);
+
+        if live(&host).is_some() {
+            return true;
+        }
+        // Walk up the superdomains looking for one that covers its subdomains.
+        let mut rest = host.as_str();
+        while let Some((_, parent)) = rest.split_once('.') {
+            if live(parent).is_some_and(|entry| entry.include_subdomains) {
+                return true;
+            }
+            rest = parent;
+        }
+        false
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let now = now();
+        let mut contents = String::new();
+        for (host, entry) in &self.entries {
+            if entry.expires > now {
+                contents.push_str(&format!(
+                    "{} {} {}\n",
+                    host, entry.expires, entry.include_subdomains as u8
+                ));
+            }
+        }
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        if let Err(e) = std::fs::write(path, contents) {
+            eprintln!("Failed to save HSTS list to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn default_path() -> Option<PathBuf> {
+    // Tests mustn't read or write the real list.
+    if cfg!(test) {
+        return None;
+    }
+    if let Some(path) = std::env::var_os("BROWSER_HSTS_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(
+        PathBuf::from(home)
+            .join(".browser-engineering")
+            .join("hsts"),
+    )
+}
+
+/// The list every request shares, loaded from disk the first time it's needed.
+pub fn store() -> std::sync::MutexGuard<'static, HstsStore> {
+    static STORE: OnceLock<Mutex<HstsStore>> = OnceLock::new();
+    STORE
+        .get_or_init(|| {
+            Mutex::new(match default_path() {
+                Some(path) => HstsStore::load(path),
+                None => HstsStore::default(),
+            })
+        })
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[test]
+fn test_hsts_policy() {
+    assert_eq!(
+        StsPolicy::parse("max-age=\"31536000\"; includeSubDomains"),
+        Some(StsPolicy {
+            max_age: 31536000,
+            include_subdomains: true
+        })
+    );
+    assert_eq!(StsPolicy::parse("includeSubDomains"), None);
+    assert_eq!(StsPolicy::parse("max-age=1; max-age=2"), None);
+
+    let mut store = HstsStore::default();
+    store.observe("Example.com", "max-age=600; includeSubDomains");
+    store.observe("other.org", "max-age=600");
+    store.observe("127.0.0.1", "max-age=600");
+    assert!(store.should_upgrade("example.com"));
+    assert!(store.should_upgrade("www.EXAMPLE.com"));
+    assert!(store.should_upgrade("other.org"));
+    assert!(!store.should_upgrade("www.other.org"));
+    assert!(!store.should_upgrade("127.0.0.1"));
+
+    // max-age=0 tells us to forget the host.
+    store.observe("example.com", "max-age=0");
+    assert!(!store.should_upgrade("example.com"));
+}
+
+#[test]
+fn test_hsts_saving() {
+    let path = std::env::temp_dir().join(format!("hsts-test-{}", std::process::id()));
+    let mut store = HstsStore::load(path.clone());
+    store.observe("example.com", "max-age=600");
+    assert!(
+        std::fs::read_to_string(&path)
+            .unwrap()
+            .starts_with("example.com ")
+    );
+    assert!(HstsStore::load(path.clone()).should_upgrade("example.com"));
+
+    // Hearing the same thing again doesn't touch the file, a real change does.
+    std::fs::remove_file(&path).unwrap();
+    store.observe("example.com", "max-age=600");
+    store.observe("example.com", "max-age=601");
+    assert!(!path.exists());
+    store.observe("example.com", "max-age=600; includeSubDomains");
+    assert!(path.exists());
+    store.observe("example.com", "max-age=0");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+    let _ = std::fs::remove_file(&path);
+}
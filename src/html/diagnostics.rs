@@ -0,0 +1,121 @@
+/*
+    Where things are in the source, and what went wrong there. HTML never fails to parse, but
+    the spec names plenty of parse errors that browsers quietly recover from: an end tag with
+    nothing to close, an element left open, an `&` that isn't a character reference. When a
+    page comes out looking odd these usually say why, so the parser keeps them around.
+*/
+
+/// A place in the source. Both start at 1, and columns count characters rather than bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The stretch of source a token or node came from, `end` is just past its last character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Works out positions from byte offsets, for one source text.
+#[derive(Debug, Clone)]
+pub struct LineIndex {
+    /// The byte offset of the start of every line.
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(source: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        LineIndex { line_starts }
+    }
+
+    /// Take in the lines of `source` from `from` on, for source that's grown since.
+    pub fn extend(&mut self, source: &str, from: usize) {
+        let lines = source[from..]
+            .match_indices('\n')
+            .map(|(i, _)| from + i + 1);
+        self.line_starts.extend(lines);
+    }
+
+    pub fn position(&self, source: &str, offset: usize) -> Position {
+        let line = self.line_starts.partition_point(|&start| start <= offset);
+        let line_start = self.line_starts[line - 1];
+        Position {
+            line,
+            column: source[line_start..offset].chars().count() + 1,
+        }
+    }
+
+    pub fn span(&self, source: &str, start: usize, end: usize) -> Span {
+        Span {
+            start: self.position(source, start),
+            end: self.position(source, end),
+        }
+    }
+}
+
+/// Something the parser had to recover from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl ParseError {
+    /// The error along with the line it's on and a marker under the bad part, like:
+    ///
+    /// ```text
+    /// 2:9: unexpected end tag </div>
+    ///   |
+    /// 2 | <p>hello</div>
+    ///   |         ^^^^^^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let Span { start, end } = self.span;
+        let line = source
+            .lines()
+            .nth(start.line - 1)
+            .unwrap_or_default()
+            .replace('\t', " ");
+        // Spans over several lines get marked to the end of the first.
+        let width = if end.line == start.line {
+            end.column.saturating_sub(start.column)
+        } else {
+            (line.chars().count() + 1).saturating_sub(start.column)
+        };
+        let gutter = " ".repeat(start.line.to_string().len());
+        format!(
+            "{}:{}: {}\n{} |\n{} | {}\n{} | {}{}",
+            start.line,
+            start.column,
+            self.message,
+            gutter,
+            start.line,
+            line,
+            gutter,
+            " ".repeat(start.column - 1),
+            "^".repeat(width.max(1))
+        )
+    }
+}
+
+#[test]
+fn test_render() {
+    let source = "<p>\n<p>hello</div>\n";
+    let index = LineIndex::new(source);
+    assert_eq!(index.position(source, 0), Position { line: 1, column: 1 });
+    assert_eq!(index.position(source, 4), Position { line: 2, column: 1 });
+    let error = ParseError {
+        message: "unexpected end tag </div>".to_string(),
+        span: index.span(source, 12, 18),
+    };
+    assert_eq!(
+        error.render(source),
+        "2:9: unexpected end tag </div>\n  |\n2 | <p>hello</div>\n  |         ^^^^^^"
+    );
+}
@@ -0,0 +1,530 @@
+/*
+    A parsed document and the ways around it. The parser hands back a flat `Vec<Node>` that
+    points at itself with indexes, which is fine for building it but easy to get wrong from the
+    outside. `Document` owns that vec and only gives out `NodeId`s, with the usual DOM lookups
+    (by id, by tag name, by CSS selector) and iterators over parents, siblings and descendants.
+
+    Changes go through `Document` too, and each one leaves a `MutationRecord` behind, like a
+    MutationObserver sees in browsers, so whatever styled or laid out the old tree knows which
+    parts need redoing. Removed nodes stay in the vec, detached, so their ids never dangle.
+*/
+
+use super::selector::Selector;
+use super::{Doctype, HTMLParser};
+use crate::layout::text::{Element, Node, NodeType};
+
+/// A node in a `Document`. Only means something to the document it came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    pub(crate) fn new(index: usize) -> Self {
+        NodeId(index)
+    }
+
+    /// Where the node is in `Document::nodes`.
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Document {
+    nodes: Vec<Node>,
+    /// The doctype the page started with, if any.
+    pub doctype: Option<Doctype>,
+    /// The top level nodes, in order. Other nodes without a parent are detached.
+    roots: Vec<NodeId>,
+    /// Changes since the last `take_records`.
+    records: Vec<MutationRecord>,
+}
+
+/// A change to the tree, see `Document::take_records`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MutationRecord {
+    /// Nodes were added to or removed from `target`'s children. None is the document itself,
+    /// for changes to the top level nodes.
+    ChildList {
+        target: Option<NodeId>,
+        added: Vec<NodeId>,
+        removed: Vec<NodeId>,
+    },
+    /// An attribute was set or removed. `old_value` is None if it wasn't there before.
+    Attributes {
+        target: NodeId,
+        name: String,
+        old_value: Option<String>,
+    },
+    /// A text or comment node's text changed.
+    CharacterData { target: NodeId, old_value: String },
+}
+
+impl Document {
+    pub fn parse(html: &str) -> Self {
+        let mut parser = HTMLParser::new(html.to_string());
+        parser.finish();
+        parser.into_document()
+    }
+
+    /// Wrap a tree straight from `HTMLParser::parse`.
+    pub fn from_nodes(nodes: Vec<Node>) -> Self {
+        let roots = (0..nodes.len())
+            .filter(|&idx| nodes[idx].parent.is_none())
+            .map(NodeId)
+            .collect();
+        Document {
+            nodes,
+            doctype: None,
+            roots,
+            records: Vec::new(),
+        }
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Hand the nodes back, e.g. to the parser that lent them out.
+    pub(super) fn into_nodes(self) -> Vec<Node> {
+        self.nodes
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    /// None if the node is text or a comment.
+    pub fn element(&self, id: NodeId) -> Option<&Element> {
+        match &self.node(id).node_type {
+            NodeType::Element(element) => Some(element),
+            NodeType::Text { .. } | NodeType::Comment { .. } => None,
+        }
+    }
+
+    /// The nodes at the top of the tree, the <html> element and any comments around it.
+    pub fn roots(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.roots.iter().copied()
+    }
+
+    /// The <html> element.
+    pub fn document_element(&self) -> Option<NodeId> {
+        self.roots().find(|&id| self.element(id).is_some())
+    }
+
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.node(id).parent.map(NodeId)
+    }
+
+    pub fn children(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.node(id).children.iter().map(|&idx| NodeId(idx))
+    }
+
+    /// The node's parent, its parent's parent and so on up to the root.
+    pub fn ancestors(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.parent(id), |&id| self.parent(id))
+    }
+
+    /// Everything inside the node in document order, not including the node itself.
+    pub fn descendants(&self, id: NodeId) -> Descendants<'_> {
+        Descendants {
+            document: self,
+            stack: self
+                .node(id)
+                .children
+                .iter()
+                .rev()
+                .map(|&idx| NodeId(idx))
+                .collect(),
+        }
+    }
+
+    /// Every node in document order, starting from the roots.
+    pub fn all_nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.roots()
+            .flat_map(|root| std::iter::once(root).chain(self.descendants(root)))
+    }
+
+    /// The children of the node's parent, the node included. Roots are each other's siblings,
+    /// and a detached node has none.
+    fn siblings(&self, id: NodeId) -> Vec<NodeId> {
+        match self.parent(id) {
+            Some(parent) => self.children(parent).collect(),
+            None if self.roots.contains(&id) => self.roots.clone(),
+            None => vec![id],
+        }
+    }
+
+    pub fn previous_sibling(&self, id: NodeId) -> Option<NodeId> {
+        let siblings = self.siblings(id);
+        let position = siblings.iter().position(|&sibling| sibling == id)?;
+        position.checked_sub(1).map(|position| siblings[position])
+    }
+
+    pub fn next_sibling(&self, id: NodeId) -> Option<NodeId> {
+        let siblings = self.siblings(id);
+        let position = siblings.iter().position(|&sibling| sibling == id)?;
+        siblings.get(position + 1).copied()
+    }
+
+    /// The siblings before the node, closest first.
+    pub fn preceding_siblings(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.previous_sibling(id), |&id| self.previous_sibling(id))
+    }
+
+    /// The siblings after the node, closest first.
+    pub fn following_siblings(&self, id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        std::iter::successors(self.next_sibling(id), |&id| self.next_sibling(id))
+    }
+
+    /// All the text in the node and everything inside it, joined together. Comments don't count.
+    pub fn text_content(&self, id: NodeId) -> String {
+        std::iter::once(id)
+            .chain(self.descendants(id))
+            .filter_map(|id| match &self.node(id).node_type {
+                NodeType::Text { text } => Some(text.as_str()),
+                NodeType::Element(_) | NodeType::Comment { .. } => None,
+            })
+            .collect()
+    }
+
+    /// The first element with this `id` attribute.
+    pub fn get_element_by_id(&self, id: &str) -> Option<NodeId> {
+        self.all_nodes()
+            .find(|&node| self.element(node).and_then(|e| e.attribute("id")) == Some(id))
+    }
+
+    /// Every element with this tag name, or every element at all for `*`.
+    pub fn get_elements_by_tag_name(&self, tag: &str) -> Vec<NodeId> {
+        let tag = tag.to_ascii_lowercase();
+        self.all_nodes()
+            .filter(|&node| {
+                self.element(node)
+                    .is_some_and(|element| tag == "*" || element.tag == tag)
+            })
+            .collect()
+    }
+
+    /// The first element matching a CSS selector like `ul > li.done a[href]`.
+    pub fn query_selector(&self, selector: &str) -> Result<Option<NodeId>, String> {
+        let selector = Selector::parse(selector)?;
+        Ok(self.all_nodes().find(|&node| selector.matches(self, node)))
+    }
+
+    /// Every element matching a CSS selector, in document order.
+    pub fn query_selector_all(&self, selector: &str) -> Result<Vec<NodeId>, String> {
+        let selector = Selector::parse(selector)?;
+        Ok(self
+            .all_nodes()
+            .filter(|&node| selector.matches(self, node))
+            .collect())
+    }
+}
+
+impl Document {
+    /// A new element that isn't in the tree yet.
+    pub fn create_element(&mut self, tag: &str) -> NodeId {
+        self.create(NodeType::Element(Element::new(tag.to_ascii_lowercase())))
+    }
+
+    /// A new text node that isn't in the tree yet.
+    pub fn create_text_node(&mut self, text: &str) -> NodeId {
+        self.create(NodeType::Text {
+            text: text.to_string(),
+        })
+    }
+
+    /// A new comment that isn't in the tree yet.
+    pub fn create_comment(&mut self, text: &str) -> NodeId {
+        self.create(NodeType::Comment {
+            text: text.to_string(),
+        })
+    }
+
+    fn create(&mut self, node_type: NodeType) -> NodeId {
+        self.nodes.push(Node {
+            node_type,
+            children: vec![],
+            parent: None,
+            span: None,
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Everything that's changed since last time, oldest first.
+    pub fn take_records(&mut self) -> Vec<MutationRecord> {
+        std::mem::take(&mut self.records)
+    }
+
+    pub fn append_child(&mut self, parent: NodeId, child: NodeId) -> Result<(), String> {
+        self.insert_before(parent, child, None)
+    }
+
+    /// Put `child` into `parent` just before `reference`, or at the end for None. A node that's
+    /// already in the tree is moved, leaving a record for its old parent as well.
+    pub fn insert_before(
+        &mut self,
+        parent: NodeId,
+        child: NodeId,
+        reference: Option<NodeId>,
+    ) -> Result<(), String> {
+        if self.element(parent).is_none() {
+            return Err("Only elements can have children".to_string());
+        }
+        if child == parent || self.ancestors(parent).any(|ancestor| ancestor == child) {
+            return Err("Can't insert a node inside itself".to_string());
+        }
+        if reference.is_some_and(|reference| self.parent(reference) != Some(parent)) {
+            return Err("The reference node isn't a child of the parent".to_string());
+        }
+        if reference == Some(child) {
+            return Ok(());
+        }
+        self.remove(child);
+        let children = &mut self.nodes[parent.0].children;
+        let position = reference
+            .and_then(|reference| children.iter().position(|&idx| idx == reference.0))
+            .unwrap_or(children.len());
+        children.insert(position, child.0);
+        self.nodes[child.0].parent = Some(parent.0);
+        self.records.push(MutationRecord::ChildList {
+            target: Some(parent),
+            added: vec![child],
+            removed: vec![],
+        });
+        Ok(())
+    }
+
+    /// Take a node out of the tree, along with everything in it. It can be put back later.
+    pub fn remove(&mut self, id: NodeId) {
+        match self.parent(id) {
+            Some(parent) => {
+                self.nodes[parent.0].children.retain(|&idx| idx != id.0);
+                self.nodes[id.0].parent = None;
+                self.records.push(MutationRecord::ChildList {
+                    target: Some(parent),
+                    added: vec![],
+                    removed: vec![id],
+                });
+            }
+            // Top level nodes are the document's children.
+            None if self.roots.contains(&id) => {
+                self.roots.retain(|&root| root != id);
+                self.records.push(MutationRecord::ChildList {
+                    target: None,
+                    added: vec![],
+                    removed: vec![id],
+                });
+            }
+            // Already detached.
+            None => {}
+        }
+    }
+
+    /// Set an attribute on an element, replacing any value it had.
+    pub fn set_attribute(&mut self, id: NodeId, name: &str, value: &str) -> Result<(), String> {
+        let element = self.element_mut(id)?;
+        let name = name.to_ascii_lowercase();
+        let old_value = element.attribute(&name).map(str::to_string);
+        element.set_attribute(&name, value);
+        self.records.push(MutationRecord::Attributes {
+            target: id,
+            name,
+            old_value,
+        });
+        Ok(())
+    }
+
+    /// Remove an attribute from an element. Does nothing if it isn't there.
+    pub fn remove_attribute(&mut self, id: NodeId, name: &str) -> Result<(), String> {
+        let element = self.element_mut(id)?;
+        let name = name.to_ascii_lowercase();
+        if let Some(old_value) = element.remove_attribute(&name) {
+            self.records.push(MutationRecord::Attributes {
+                target: id,
+                name,
+                old_value: Some(old_value),
+            });
+        }
+        Ok(())
+    }
+
+    fn element_mut(&mut self, id: NodeId) -> Result<&mut Element, String> {
+        match &mut self.nodes[id.0].node_type {
+            NodeType::Element(element) => Ok(element),
+            NodeType::Text { .. } | NodeType::Comment { .. } => {
+                Err("Only elements have attributes".to_string())
+            }
+        }
+    }
+
+    /// Like setting `textContent`: a text or comment node gets the new text, an element has its
+    /// children replaced by a single text node, or none at all for an empty string.
+    pub fn set_text_content(&mut self, id: NodeId, text: &str) {
+        match &mut self.nodes[id.0].node_type {
+            NodeType::Text { text: old } | NodeType::Comment { text: old } => {
+                let old_value = std::mem::replace(old, text.to_string());
+                self.records.push(MutationRecord::CharacterData {
+                    target: id,
+                    old_value,
+                });
+            }
+            NodeType::Element(_) => {
+                let removed: Vec<NodeId> = self.children(id).collect();
+                for &child in &removed {
+                    self.nodes[child.0].parent = None;
+                }
+                self.nodes[id.0].children.clear();
+                let added = if text.is_empty() {
+                    vec![]
+                } else {
+                    let child = self.create_text_node(text);
+                    self.nodes[id.0].children.push(child.0);
+                    self.nodes[child.0].parent = Some(id.0);
+                    vec![child]
+                };
+                if !added.is_empty() || !removed.is_empty() {
+                    self.records.push(MutationRecord::ChildList {
+                        target: Some(id),
+                        added,
+                        removed,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// See `Document::descendants`.
+pub struct Descendants<'a> {
+    document: &'a Document,
+    /// What's left to visit, next one last.
+    stack: Vec<NodeId>,
+}
+
+impl Iterator for Descendants<'_> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let id = self.stack.pop()?;
+        self.stack.extend(
+            self.document
+                .children(id)
+                .collect::<Vec<_>>()
+                .into_iter()
+                .rev(),
+        );
+        Some(id)
+    }
+}
+
+#[test]
+fn test_traversal() {
+    let document =
+        Document::parse("<!--c--><ul id=list><li>one</li><li>t<b>w</b>o<!--x--></li></ul>");
+    let list = document.get_element_by_id("list").unwrap();
+    let items = document.get_elements_by_tag_name("LI");
+    assert_eq!(items.len(), 2);
+    assert_eq!(document.children(list).collect::<Vec<_>>(), items);
+    assert_eq!(document.parent(items[0]), Some(list));
+    assert_eq!(document.next_sibling(items[0]), Some(items[1]));
+    assert_eq!(document.previous_sibling(items[0]), None);
+    assert_eq!(document.text_content(list), "onetwo");
+    assert_eq!(document.text_content(items[1]), "two");
+
+    let tags: Vec<&str> = document
+        .ancestors(items[1])
+        .filter_map(|id| document.element(id))
+        .map(|element| element.tag.as_str())
+        .collect();
+    assert_eq!(tags, ["ul", "body", "html"]);
+    let html = document.document_element().unwrap();
+    assert_eq!(
+        document
+            .previous_sibling(html)
+            .map(|id| document.node(id).children.len()),
+        Some(0)
+    );
+    assert_eq!(document.descendants(html).count(), 11);
+    assert_eq!(document.get_elements_by_tag_name("*").len(), 7);
+    assert_eq!(document.get_element_by_id("nope"), None);
+}
+
+#[test]
+fn test_mutation() {
+    let mut document = Document::parse("<ul><li>a</li><li>b</li></ul><p>");
+    let [ul, p] = ["ul", "p"].map(|tag| document.get_elements_by_tag_name(tag)[0]);
+    let items = document.get_elements_by_tag_name("li");
+
+    // Moving a node records it leaving one parent and joining the other.
+    document
+        .insert_before(ul, items[1], Some(items[0]))
+        .unwrap();
+    document.append_child(p, items[0]).unwrap();
+    assert_eq!(document.text_content(ul), "b");
+    assert_eq!(document.text_content(p), "a");
+    let li = document.create_element("LI");
+    document.set_text_content(li, "c");
+    document.append_child(ul, li).unwrap();
+    assert_eq!(document.text_content(ul), "bc");
+    let records = document.take_records();
+    assert_eq!(records.len(), 6);
+    assert_eq!(
+        records[2],
+        MutationRecord::ChildList {
+            target: Some(ul),
+            added: vec![],
+            removed: vec![items[0]],
+        }
+    );
+    assert_eq!(
+        records[5],
+        MutationRecord::ChildList {
+            target: Some(ul),
+            added: vec![li],
+            removed: vec![],
+        }
+    );
+
+    document.set_attribute(p, "Class", "x").unwrap();
+    document.set_attribute(p, "class", "y").unwrap();
+    document.remove_attribute(p, "class").unwrap();
+    document.remove_attribute(p, "class").unwrap();
+    let old_values: Vec<Option<String>> = document
+        .take_records()
+        .into_iter()
+        .map(|record| match record {
+            MutationRecord::Attributes { old_value, .. } => old_value,
+            record => panic!("not an attribute change: {:?}", record),
+        })
+        .collect();
+    assert_eq!(
+        old_values,
+        [None, Some("x".to_string()), Some("y".to_string())]
+    );
+
+    let text = document.children(li).next().unwrap();
+    document.set_text_content(text, "d");
+    document.remove(li);
+    assert_eq!(document.text_content(ul), "b");
+    assert_eq!(document.text_content(li), "d");
+    assert_eq!(document.parent(li), None);
+    assert_eq!(document.next_sibling(li), None);
+    assert!(document.append_child(text, li).is_err());
+    assert!(document.append_child(items[1], ul).is_err());
+    assert!(document.insert_before(ul, li, Some(p)).is_err());
+    assert_eq!(document.take_records().len(), 2);
+
+    // Taking away a top level node is a change to the document itself, once.
+    let html = document.document_element().unwrap();
+    document.remove(html);
+    document.remove(html);
+    assert_eq!(document.document_element(), None);
+    assert_eq!(
+        document.take_records(),
+        [MutationRecord::ChildList {
+            target: None,
+            added: vec![],
+            removed: vec![html],
+        }]
+    );
+}
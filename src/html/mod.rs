@@ -0,0 +1,1507 @@
+pub mod diagnostics;
+pub mod dom;
+pub mod entities;
+pub mod selector;
+pub mod serialize;
+pub mod tokenizer;
+
+use crate::encoding::{Decoder, Encoding};
+use crate::layout::text::{Element, Node, NodeType};
+use diagnostics::{ParseError, Span};
+use dom::{Document, MutationRecord, NodeId};
+use tokenizer::{Token, Tokenizer, content_state};
+
+/// Elements that are fine to leave without an end tag, it's implied by whatever comes next.
+const IMPLIED_END_TAGS: [&str; 19] = [
+    "body", "dd", "dt", "head", "html", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+    "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+/// Elements that can never have children, so they're finished as soon as they open.
+pub const VOID_ELEMENTS: [&str; 14] = [
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Elements whose content is taken as is, a `<` inside them doesn't start a tag.
+pub const RAW_TEXT_ELEMENTS: [&str; 6] =
+    ["script", "style", "xmp", "iframe", "noembed", "noframes"];
+
+/// Like raw text, but character references still get decoded.
+pub const RCDATA_ELEMENTS: [&str; 2] = ["textarea", "title"];
+
+/// Elements that belong in the <head>, anything else starts the <body>.
+const HEAD_TAGS: [&str; 9] = [
+    "base", "basefont", "bgsound", "noscript", "link", "meta", "title", "style", "script",
+];
+
+/// Start tags that close an open <p>, since a paragraph can't contain them.
+const CLOSES_P: [&str; 38] = [
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "center",
+    "dd",
+    "details",
+    "dialog",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hgroup",
+    "hr",
+    "li",
+    "listing",
+    "main",
+    "menu",
+    "nav",
+    "ol",
+    "plaintext",
+    "pre",
+    "search",
+    "section",
+    "summary",
+    "ul",
+];
+
+/// Elements that get reopened when they're closed too early, so in `<p><b>x<p>y` the second
+/// paragraph is still bold.
+const FORMATTING_ELEMENTS: [&str; 14] = [
+    "a", "b", "big", "code", "em", "font", "i", "nobr", "s", "small", "strike", "strong", "tt", "u",
+];
+
+/// Elements that formatting doesn't carry into from outside.
+const MARKER_ELEMENTS: [&str; 7] = [
+    "applet", "caption", "marquee", "object", "td", "template", "th",
+];
+
+/// https://html.spec.whatwg.org/multipage/parsing.html#special, end tags of other elements can't
+/// close anything past one of these.
+const SPECIAL_ELEMENTS: [&str; 90] = [
+    "address",
+    "annotation-xml",
+    "applet",
+    "area",
+    "article",
+    "aside",
+    "base",
+    "basefont",
+    "bgsound",
+    "blockquote",
+    "body",
+    "br",
+    "button",
+    "caption",
+    "center",
+    "col",
+    "colgroup",
+    "dd",
+    "desc",
+    "details",
+    "dir",
+    "div",
+    "dl",
+    "dt",
+    "embed",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "foreignobject",
+    "form",
+    "frame",
+    "frameset",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "head",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "iframe",
+    "img",
+    "input",
+    "keygen",
+    "li",
+    "link",
+    "listing",
+    "main",
+    "marquee",
+    "menu",
+    "meta",
+    "mi",
+    "mn",
+    "mo",
+    "ms",
+    "mtext",
+    "nav",
+    "noembed",
+    "noframes",
+    "noscript",
+    "object",
+    "ol",
+    "p",
+    "param",
+    "plaintext",
+    "pre",
+    "script",
+    "search",
+    "section",
+    "select",
+    "source",
+    "style",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "template",
+    "textarea",
+    "tfoot",
+    "th",
+    "thead",
+    "title",
+    "tr",
+    "track",
+    "ul",
+    "wbr",
+];
+
+/// An element is only in scope if there's none of these between it and the current node, so a
+/// `</b>` inside a <td> can't close a <b> outside the table.
+const SCOPE_BOUNDARIES: [&str; 9] = [
+    "applet", "caption", "html", "marquee", "object", "table", "td", "template", "th",
+];
+
+/// https://html.spec.whatwg.org/multipage/parsing.html#the-initial-insertion-mode, any public id
+/// starting with one of these puts the document in quirks mode.
+const QUIRKS_PUBLIC_ID_PREFIXES: [&str; 55] = [
+    "+//silmaril//dtd html pro v0r11 19970101//",
+    "-//as//dtd html 3.0 aswedit + extensions//",
+    "-//advasoft ltd//dtd html 3.0 aswedit + extensions//",
+    "-//ietf//dtd html 2.0 level 1//",
+    "-//ietf//dtd html 2.0 level 2//",
+    "-//ietf//dtd html 2.0 strict level 1//",
+    "-//ietf//dtd html 2.0 strict level 2//",
+    "-//ietf//dtd html 2.0 strict//",
+    "-//ietf//dtd html 2.0//",
+    "-//ietf//dtd html 2.1e//",
+    "-//ietf//dtd html 3.0//",
+    "-//ietf//dtd html 3.2 final//",
+    "-//ietf//dtd html 3.2//",
+    "-//ietf//dtd html 3//",
+    "-//ietf//dtd html level 0//",
+    "-//ietf//dtd html level 1//",
+    "-//ietf//dtd html level 2//",
+    "-//ietf//dtd html level 3//",
+    "-//ietf//dtd html strict level 0//",
+    "-//ietf//dtd html strict level 1//",
+    "-//ietf//dtd html strict level 2//",
+    "-//ietf//dtd html strict level 3//",
+    "-//ietf//dtd html strict//",
+    "-//ietf//dtd html//",
+    "-//metrius//dtd metrius presentational//",
+    "-//microsoft//dtd internet explorer 2.0 html strict//",
+    "-//microsoft//dtd internet explorer 2.0 html//",
+    "-//microsoft//dtd internet explorer 2.0 tables//",
+    "-//microsoft//dtd internet explorer 3.0 html strict//",
+    "-//microsoft//dtd internet explorer 3.0 html//",
+    "-//microsoft//dtd internet explorer 3.0 tables//",
+    "-//netscape comm. corp.//dtd html//",
+    "-//netscape comm. corp.//dtd strict html//",
+    "-//o'reilly and associates//dtd html 2.0//",
+    "-//o'reilly and associates//dtd html extended 1.0//",
+    "-//o'reilly and associates//dtd html extended relaxed 1.0//",
+    "-//sq//dtd html 2.0 hotmetal + extensions//",
+    "-//softquad software//dtd hotmetal pro 6.0::19990601::extensions to html 4.0//",
+    "-//softquad//dtd hotmetal pro 4.0::19971010::extensions to html 4.0//",
+    "-//spyglass//dtd html 2.0 extended//",
+    "-//sun microsystems corp.//dtd hotjava html//",
+    "-//sun microsystems corp.//dtd hotjava strict html//",
+    "-//w3c//dtd html 3 1995-03-24//",
+    "-//w3c//dtd html 3.2 draft//",
+    "-//w3c//dtd html 3.2 final//",
+    "-//w3c//dtd html 3.2//",
+    "-//w3c//dtd html 3.2s draft//",
+    "-//w3c//dtd html 4.0 frameset//",
+    "-//w3c//dtd html 4.0 transitional//",
+    "-//w3c//dtd html experimental 19960712//",
+    "-//w3c//dtd html experimental 970421//",
+    "-//w3c//dtd w3 html//",
+    "-//w3o//dtd w3 html 3.0//",
+    "-//webtechs//dtd mozilla html 2.0//",
+    "-//webtechs//dtd mozilla html//",
+];
+
+/// HTML 4.01 doctypes are quirky without a system id and only a little quirky with one.
+const HTML_401_PUBLIC_ID_PREFIXES: [&str; 2] = [
+    "-//w3c//dtd html 4.01 frameset//",
+    "-//w3c//dtd html 4.01 transitional//",
+];
+
+const LIMITED_QUIRKS_PUBLIC_ID_PREFIXES: [&str; 2] = [
+    "-//w3c//dtd xhtml 1.0 frameset//",
+    "-//w3c//dtd xhtml 1.0 transitional//",
+];
+
+/// How closely layout should follow the standards, old pages relied on old browsers' bugs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
+/// `<!DOCTYPE name PUBLIC "public id" "system id">`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Doctype {
+    pub name: Option<String>,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    /// Set when the doctype is too broken to trust.
+    pub force_quirks: bool,
+}
+
+impl Doctype {
+    pub fn quirks_mode(&self) -> QuirksMode {
+        let public_id = self
+            .public_id
+            .as_deref()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let system_id = self.system_id.as_deref().map(str::to_ascii_lowercase);
+        let public_starts = |prefixes: &[&str]| prefixes.iter().any(|p| public_id.starts_with(p));
+
+        if self.force_quirks
+            || self.name.as_deref() != Some("html")
+            || matches!(
+                public_id.as_str(),
+                "-//w3o//dtd w3 html strict 3.0//en//"
+                    | "-/w3c/dtd html 4.0 transitional/en"
+                    | "html"
+            )
+            || system_id.as_deref()
+                == Some("http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd")
+            || public_starts(&QUIRKS_PUBLIC_ID_PREFIXES)
+            || (system_id.is_none() && public_starts(&HTML_401_PUBLIC_ID_PREFIXES))
+        {
+            QuirksMode::Quirks
+        } else if public_starts(&LIMITED_QUIRKS_PUBLIC_ID_PREFIXES)
+            || public_starts(&HTML_401_PUBLIC_ID_PREFIXES)
+        {
+            QuirksMode::LimitedQuirks
+        } else {
+            QuirksMode::NoQuirks
+        }
+    }
+}
+
+/// An entry in the list of active formatting elements.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Formatting {
+    Element(usize),
+    /// Put there by the element it holds, e.g. a <td>. Formatting from before it stays outside.
+    Marker(usize),
+}
+
+/// Parses a whole page with `new` and `parse`, or one arriving over the network with
+/// `streaming`, `feed` and `finish`, which say what changed in the tree so far as they go.
+pub struct HTMLParser {
+    tokenizer: Tokenizer,
+    decoder: Decoder,
+    /// A newline straight after <pre> is just there to make the source look nice.
+    skip_newline: bool,
+    /// Set once the end of the document has been dealt with.
+    finished: bool,
+    /// Changes to the tree that haven't been handed out yet.
+    records: Vec<MutationRecord>,
+    unfinished: Vec<usize>, // Indexes in vec of unfinisehd nodes
+    nodes: Vec<Node>,       // Vec of nodes finished or unfinished!
+    /// The formatting elements that are open, or were closed too early and need reopening.
+    formatting: Vec<Formatting>,
+    /// The document's doctype, if it had one.
+    pub doctype: Option<Doctype>,
+    /// Where the token being handled is in the source.
+    span: Span,
+    /// What the parser had to recover from, in source order more or less.
+    pub errors: Vec<ParseError>,
+}
+
+impl HTMLParser {
+    pub fn new(body: String) -> Self {
+        let mut parser = HTMLParser::streaming(None);
+        parser.tokenizer = Tokenizer::new(&body);
+        parser
+    }
+
+    /// A parser for a page that'll arrive in chunks. `content_type` is the Content-Type header,
+    /// for its charset.
+    pub fn streaming(content_type: Option<&str>) -> Self {
+        HTMLParser {
+            tokenizer: Tokenizer::streaming(),
+            decoder: Decoder::new(content_type),
+            skip_newline: false,
+            finished: false,
+            records: Vec::new(),
+            unfinished: Vec::new(),
+            nodes: Vec::new(),
+            formatting: Vec::new(),
+            doctype: None,
+            span: Span::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Parse the whole document.
+    pub fn parse(&mut self) -> Vec<Node> {
+        self.finish();
+        self.nodes.clone()
+    }
+
+    /// Parse as much as possible of the next chunk of the page. What's parsed so far is a tree
+    /// like any other, see `nodes`, so it can be laid out while the rest is downloading.
+    /// Returns how that tree changed.
+    pub fn feed(&mut self, bytes: &[u8]) -> Vec<MutationRecord> {
+        let text = self.decoder.decode(bytes);
+        self.tokenizer.feed(&text);
+        self.run();
+        std::mem::take(&mut self.records)
+    }
+
+    /// The page is all there: parse the rest of it and close anything still open. Returns the
+    /// changes to the tree since the last `feed`.
+    pub fn finish(&mut self) -> Vec<MutationRecord> {
+        if !self.finished {
+            self.finished = true;
+            let text = self.decoder.finish();
+            self.tokenizer.feed(&text);
+            self.tokenizer.finish();
+            self.run();
+
+            let errors = self.unclosed(&self.unfinished);
+            self.errors.extend(errors);
+
+            // Even an empty document gets its <html>, <head> and <body>.
+            if !self.has_element("body") {
+                self.unfinished.truncate(2);
+                self.implicit_tags(None);
+            }
+        }
+        std::mem::take(&mut self.records)
+    }
+
+    /// The encoding the page is being decoded with, None until there's been enough to tell.
+    pub fn encoding(&self) -> Option<Encoding> {
+        self.decoder.encoding()
+    }
+
+    /// The tree as far as it's been parsed, the same nodes `parse` returns.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Look at the tree so far as a `Document`, e.g. to lay it out while the rest is downloading.
+    /// The nodes are lent to it rather than copied, so this stays cheap however big the page is.
+    pub fn with_document<R>(&mut self, f: impl FnOnce(&Document) -> R) -> R {
+        let mut document = Document::from_nodes(std::mem::take(&mut self.nodes));
+        document.doctype = self.doctype.clone();
+        let result = f(&document);
+        self.nodes = document.into_nodes();
+        result
+    }
+
+    /// The tree along with its doctype.
+    pub fn into_document(self) -> Document {
+        let mut document = Document::from_nodes(self.nodes);
+        document.doctype = self.doctype;
+        document
+    }
+
+    /// Handle every token the tokenizer has ready.
+    fn run(&mut self) {
+        loop {
+            self.tokenizer.allow_cdata = self.in_foreign_content();
+            let next = self.tokenizer.next_token();
+            self.errors.extend(self.tokenizer.take_errors());
+            let Some((token, span)) = next else {
+                break;
+            };
+            self.span = span;
+            let after_pre = std::mem::take(&mut self.skip_newline);
+            match token {
+                Token::Text(text) if after_pre && text.starts_with('\n') => {
+                    self.add_text(&text[1..])
+                }
+                Token::Text(text) => self.add_text(&text),
+                Token::StartTag {
+                    element,
+                    self_closing,
+                } => {
+                    self.skip_newline =
+                        matches!(element.tag.as_str(), "pre" | "listing" | "textarea");
+                    self.add_tag(element, self_closing);
+                    if let Some(state) = self.raw_text_element().and_then(|tag| content_state(&tag))
+                    {
+                        self.tokenizer.set_state(state);
+                    }
+                }
+                Token::EndTag { name } => self.end_tag(&name),
+                Token::Comment(text) => self.add_comment(text),
+                // Only a doctype before everything but comments counts.
+                Token::Doctype(doctype) if self.unfinished.is_empty() => {
+                    self.doctype = Some(doctype);
+                }
+                Token::Doctype(_) => {}
+            }
+        }
+    }
+
+    /// Quirks mode is decided by the doctype, and having none at all means quirks.
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.doctype
+            .as_ref()
+            .map_or(QuirksMode::Quirks, Doctype::quirks_mode)
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push(ParseError {
+            message,
+            span: self.span,
+        });
+    }
+
+    /// An end tag is about to close the element at `position` in `unfinished`. Anything still
+    /// open inside it should have been closed first.
+    fn check_unclosed(&mut self, position: usize) {
+        let errors = self.unclosed(&self.unfinished[position + 1..]);
+        self.errors.extend(errors);
+    }
+
+    /// Errors for the elements in `open` that needed an end tag, pointing at their start tags.
+    /// Formatting that got reopened shares its start tag, so it's only reported the once.
+    fn unclosed(&self, open: &[usize]) -> Vec<ParseError> {
+        open.iter()
+            .filter(|&&idx| !IMPLIED_END_TAGS.contains(&self.tag(idx)))
+            .map(|&idx| ParseError {
+                message: format!("unclosed element <{}>", self.tag(idx)),
+                span: self.nodes[idx].span.unwrap_or(self.span),
+            })
+            .filter(|error| !self.errors.contains(error))
+            .collect()
+    }
+
+    /// The name of the current element if it's one whose content is raw text or RCDATA.
+    fn raw_text_element(&self) -> Option<String> {
+        let tag = self.open_tags().last()?.to_string();
+        (RAW_TEXT_ELEMENTS.contains(&tag.as_str()) || RCDATA_ELEMENTS.contains(&tag.as_str()))
+            .then_some(tag)
+    }
+
+    fn add_comment(&mut self, text: String) {
+        let parent = self.unfinished.last().cloned();
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            node_type: NodeType::Comment { text },
+            children: vec![],
+            parent,
+            span: Some(self.span),
+        });
+        if let Some(parent_idx) = parent {
+            self.nodes[parent_idx].children.push(idx);
+        }
+        self.record_added(parent, idx);
+    }
+
+    fn add_text(&mut self, text: &str) {
+        // Script, style etc. take everything up to their end tag as text.
+        if self.raw_text_element().is_some() {
+            return self.insert_text(text);
+        }
+        // Before the body, whitespace only counts inside the <head> or after it, and doesn't
+        // start the body like other text does.
+        let mut text = text;
+        if !self.has_element("body") {
+            let rest = text.trim_start_matches(|c: char| c.is_ascii_whitespace());
+            match self.open_tags().last() {
+                Some(&"head") => self.insert_text(&text[..text.len() - rest.len()]),
+                Some(&"html") if self.has_element("head") => {
+                    self.insert_text(&text[..text.len() - rest.len()])
+                }
+                _ => {}
+            }
+            text = rest;
+            if text.is_empty() {
+                return;
+            }
+        }
+        self.implicit_tags(None);
+        self.reconstruct_formatting();
+        self.insert_text(text);
+    }
+
+    /// Text goes under the current node, joining on to the text before it if there is some.
+    fn insert_text(&mut self, text: &str) {
+        let Some(&parent_idx) = self.unfinished.last() else {
+            return;
+        };
+        if text.is_empty() {
+            return;
+        }
+        if let Some(&last) = self.nodes[parent_idx].children.last()
+            && let NodeType::Text { text: previous } = &mut self.nodes[last].node_type
+        {
+            self.records.push(MutationRecord::CharacterData {
+                target: NodeId::new(last),
+                old_value: previous.clone(),
+            });
+            previous.push_str(text);
+            if let Some(span) = &mut self.nodes[last].span {
+                span.end = self.span.end;
+            }
+            return;
+        }
+        let idx = self.nodes.len();
+        self.nodes.push(Node {
+            node_type: NodeType::Text {
+                text: text.to_string(),
+            },
+            children: vec![],
+            parent: Some(parent_idx),
+            span: Some(self.span),
+        });
+        self.nodes[parent_idx].children.push(idx);
+        self.record_added(Some(parent_idx), idx);
+    }
+
+    /// `parent` is None for a node added at the top level.
+    fn record_added(&mut self, parent: Option<usize>, child: usize) {
+        self.records.push(MutationRecord::ChildList {
+            target: parent.map(NodeId::new),
+            added: vec![NodeId::new(child)],
+            removed: vec![],
+        });
+    }
+
+    fn record_removed(&mut self, parent: usize, children: &[usize]) {
+        if !children.is_empty() {
+            self.records.push(MutationRecord::ChildList {
+                target: Some(NodeId::new(parent)),
+                added: vec![],
+                removed: children.iter().map(|&idx| NodeId::new(idx)).collect(),
+            });
+        }
+    }
+
+    fn add_tag(&mut self, element: Element, self_closing: bool) {
+        let tag = element.tag.clone();
+        self.implicit_tags(Some(&tag));
+        self.close_implied(&tag);
+        match tag.as_str() {
+            // Links can't nest, a new <a> closes the one that's open.
+            "a" => {
+                if let Some(a) = self.formatting_element("a") {
+                    self.adoption_agency("a");
+                    self.formatting
+                        .retain(|entry| *entry != Formatting::Element(a));
+                    self.unfinished.retain(|&idx| idx != a);
+                }
+            }
+            "nobr" if self.open_position("nobr", &SCOPE_BOUNDARIES).is_some() => {
+                self.reconstruct_formatting();
+                self.adoption_agency("nobr");
+            }
+            _ => {}
+        }
+        // Block level elements and the like start fresh, anything inline picks up the formatting
+        // that was closed too early.
+        if !CLOSES_P.contains(&tag.as_str())
+            && !HEAD_TAGS.contains(&tag.as_str())
+            && !matches!(
+                tag.as_str(),
+                "p" | "table"
+                    | "textarea"
+                    | "html"
+                    | "head"
+                    | "body"
+                    | "caption"
+                    | "col"
+                    | "colgroup"
+                    | "tbody"
+                    | "thead"
+                    | "tfoot"
+                    | "tr"
+                    | "td"
+                    | "th"
+            )
+        {
+            self.reconstruct_formatting();
+        }
+
+        // `/>` only closes void elements and svg/math ones, on a <div/> it's ignored.
+        let self_closing =
+            self_closing && (self.in_foreign_content() || tag == "svg" || tag == "math");
+        let open = !VOID_ELEMENTS.contains(&tag.as_str()) && !self_closing;
+        self.add_element(element, open);
+        let idx = self.nodes.len() - 1;
+        self.nodes[idx].span = Some(self.span);
+        if FORMATTING_ELEMENTS.contains(&tag.as_str()) {
+            self.push_formatting(idx);
+        } else if MARKER_ELEMENTS.contains(&tag.as_str()) {
+            self.formatting.push(Formatting::Marker(idx));
+        }
+    }
+
+    fn end_tag(&mut self, name: &str) {
+        let in_scope = |extra: &[&'static str]| [SCOPE_BOUNDARIES.as_slice(), extra].concat();
+        let position = match name {
+            // Browsers treat a stray </br> as <br>, and drop any other void end tag.
+            "br" => {
+                self.error("unexpected end tag </br>".to_string());
+                return self.add_tag(Element::new("br"), false);
+            }
+            _ if VOID_ELEMENTS.contains(&name) => {
+                return self.error(format!("unexpected end tag </{}>", name));
+            }
+            // Content after </body> or </html> still ends up in the body, so keep them open.
+            "body" | "html" => return,
+            _ if FORMATTING_ELEMENTS.contains(&name) => {
+                if !self.adoption_agency(name) {
+                    self.close_any_other(name);
+                }
+                return;
+            }
+            "p" => self.open_position(name, &in_scope(&["button"])),
+            "li" => self.open_position(name, &in_scope(&["ol", "ul"])),
+            "table" | "caption" | "tbody" | "thead" | "tfoot" | "tr" | "td" | "th" => {
+                self.open_position(name, &["html", "table", "template"])
+            }
+            _ if SPECIAL_ELEMENTS.contains(&name) => self.open_position(name, &SCOPE_BOUNDARIES),
+            _ => return self.close_any_other(name),
+        };
+        match position {
+            Some(position) => {
+                self.check_unclosed(position);
+                self.close_to(position);
+            }
+            // A </p> without a <p> makes an empty paragraph, anything else unmatched is dropped.
+            None => {
+                self.error(format!("unexpected end tag </{}>", name));
+                if name == "p" {
+                    self.implicit_tags(Some("p"));
+                    self.add_element(Element::new("p"), false);
+                }
+            }
+        }
+    }
+
+    /// An end tag for an ordinary element like <span> closes the innermost open one, as long as
+    /// it doesn't have to close a special element like a <div> to get there.
+    fn close_any_other(&mut self, name: &str) {
+        for position in (0..self.unfinished.len()).rev() {
+            let tag = self.tag(self.unfinished[position]);
+            if tag == name {
+                self.check_unclosed(position);
+                return self.close_to(position);
+            }
+            if SPECIAL_ELEMENTS.contains(&tag) {
+                break;
+            }
+        }
+        self.error(format!("unexpected end tag </{}>", name));
+    }
+
+    /// Close the open elements from `position` in `unfinished` on. Closing a <td>, <object> etc.
+    /// also forgets the formatting that was opened inside it.
+    fn close_to(&mut self, position: usize) {
+        let closed: Vec<usize> = self.unfinished.drain(position..).collect();
+        let marker = self
+            .formatting
+            .iter()
+            .position(|entry| matches!(entry, Formatting::Marker(owner) if closed.contains(owner)));
+        if let Some(marker) = marker {
+            self.formatting.truncate(marker);
+        }
+    }
+
+    /// The tag name of node `idx`, empty for anything that isn't an element.
+    fn tag(&self, idx: usize) -> &str {
+        match &self.nodes[idx].node_type {
+            NodeType::Element(element) => element.tag.as_str(),
+            NodeType::Text { .. } | NodeType::Comment { .. } => "",
+        }
+    }
+
+    /// The last formatting element named `tag` since the last marker.
+    fn formatting_element(&self, tag: &str) -> Option<usize> {
+        self.formatting
+            .iter()
+            .rev()
+            .map_while(|entry| match entry {
+                Formatting::Element(idx) => Some(*idx),
+                Formatting::Marker(_) => None,
+            })
+            .find(|&idx| self.tag(idx) == tag)
+    }
+
+    /// Add a formatting element to the list. Only three identical ones are kept, so that a page
+    /// full of unclosed <b>s doesn't reopen them all every time.
+    fn push_formatting(&mut self, idx: usize) {
+        let attributes = |idx: usize| match &self.nodes[idx].node_type {
+            NodeType::Element(element) => {
+                let mut attributes = element.attributes.clone();
+                attributes.sort();
+                attributes
+            }
+            NodeType::Text { .. } | NodeType::Comment { .. } => Vec::new(),
+        };
+        let same: Vec<usize> = (0..self.formatting.len())
+            .rev()
+            .map_while(|i| match self.formatting[i] {
+                Formatting::Element(other) => Some((i, other)),
+                Formatting::Marker(_) => None,
+            })
+            .filter(|&(_, other)| {
+                self.tag(other) == self.tag(idx) && attributes(other) == attributes(idx)
+            })
+            .map(|(i, _)| i)
+            .collect();
+        if let [.., earliest] = same[..]
+            && same.len() >= 3
+        {
+            self.formatting.remove(earliest);
+        }
+        self.formatting.push(Formatting::Element(idx));
+    }
+
+    /// Reopen the formatting elements that were closed before their time, so in
+    /// `<p><b>bold<p>still bold` the second paragraph gets a <b> of its own.
+    fn reconstruct_formatting(&mut self) {
+        let is_open = |entry: &Formatting| match entry {
+            Formatting::Marker(_) => true,
+            Formatting::Element(idx) => self.unfinished.contains(idx),
+        };
+        let Some(last) = self.formatting.last() else {
+            return;
+        };
+        if is_open(last) {
+            return;
+        }
+        let mut start = self.formatting.len() - 1;
+        while start > 0 && !is_open(&self.formatting[start - 1]) {
+            start -= 1;
+        }
+        for i in start..self.formatting.len() {
+            let Formatting::Element(old) = self.formatting[i] else {
+                continue;
+            };
+            let element = self.copy_element(old);
+            self.add_element(element, true);
+            let copy = self.nodes.len() - 1;
+            self.nodes[copy].span = self.nodes[old].span;
+            self.formatting[i] = Formatting::Element(copy);
+        }
+    }
+
+    fn copy_element(&self, idx: usize) -> Element {
+        match &self.nodes[idx].node_type {
+            NodeType::Element(element) => element.clone(),
+            NodeType::Text { .. } | NodeType::Comment { .. } => Element::new(""),
+        }
+    }
+
+    /// Whether the open element at `position` in `unfinished` is in scope, see `SCOPE_BOUNDARIES`.
+    fn in_scope(&self, position: usize) -> bool {
+        !self.unfinished[position + 1..]
+            .iter()
+            .any(|&idx| SCOPE_BOUNDARIES.contains(&self.tag(idx)))
+    }
+
+    /// Move node `child` to the end of `parent`'s children.
+    fn append_child(&mut self, parent: usize, child: usize) {
+        if let Some(old) = self.nodes[child].parent {
+            self.nodes[old].children.retain(|&idx| idx != child);
+            self.record_removed(old, &[child]);
+        }
+        self.nodes[child].parent = Some(parent);
+        self.nodes[parent].children.push(child);
+        self.record_added(Some(parent), child);
+    }
+
+    /// https://html.spec.whatwg.org/multipage/parsing.html#adoption-agency-algorithm
+    /// Close the formatting element `subject` even when it's misnested, like the <b> in
+    /// `<b><i></b></i>`. Elements that were opened inside it get split in two the way browsers do
+    /// it. False if there's no such formatting element, then it's an end tag like any other.
+    fn adoption_agency(&mut self, subject: &str) -> bool {
+        if let Some(&current) = self.unfinished.last()
+            && self.tag(current) == subject
+            && !self.formatting.contains(&Formatting::Element(current))
+        {
+            self.unfinished.pop();
+            return true;
+        }
+        for round in 0..8 {
+            let Some(formatting_element) = self.formatting_element(subject) else {
+                return false;
+            };
+            let entry = Formatting::Element(formatting_element);
+            let Some(element_position) = self
+                .unfinished
+                .iter()
+                .position(|&idx| idx == formatting_element)
+            else {
+                self.error(format!("unexpected end tag </{}>", subject));
+                self.formatting.retain(|other| *other != entry);
+                return true;
+            };
+            if !self.in_scope(element_position) {
+                self.error(format!("unexpected end tag </{}>", subject));
+                return true;
+            }
+            if round == 0 {
+                self.check_unclosed(element_position);
+            }
+            // The first block inside the formatting element. Without one it can just be closed.
+            let Some(block_position) = (element_position + 1..self.unfinished.len())
+                .find(|&position| SPECIAL_ELEMENTS.contains(&self.tag(self.unfinished[position])))
+            else {
+                self.close_to(element_position);
+                self.formatting.retain(|other| *other != entry);
+                return true;
+            };
+            let furthest_block = self.unfinished[block_position];
+            let common_ancestor = self.unfinished[element_position - 1];
+            let mut bookmark = self
+                .formatting
+                .iter()
+                .position(|other| *other == entry)
+                .unwrap_or_default();
+
+            // Everything between the formatting element and the block gets a copy that wraps
+            // the block, closing the originals.
+            let mut position = block_position;
+            let mut last_node = furthest_block;
+            for inner in 1.. {
+                position -= 1;
+                let node = self.unfinished[position];
+                if node == formatting_element {
+                    break;
+                }
+                let mut node_entry = self
+                    .formatting
+                    .iter()
+                    .position(|other| *other == Formatting::Element(node));
+                if inner > 3
+                    && let Some(i) = node_entry.take()
+                {
+                    self.formatting.remove(i);
+                    if i < bookmark {
+                        bookmark -= 1;
+                    }
+                }
+                let Some(node_entry) = node_entry else {
+                    self.unfinished.remove(position);
+                    continue;
+                };
+                let element = self.copy_element(node);
+                self.add_element_detached(element);
+                let copy = self.nodes.len() - 1;
+                self.nodes[copy].span = self.nodes[node].span;
+                self.formatting[node_entry] = Formatting::Element(copy);
+                self.unfinished[position] = copy;
+                if last_node == furthest_block {
+                    bookmark = node_entry + 1;
+                }
+                self.append_child(copy, last_node);
+                last_node = copy;
+            }
+            self.append_child(common_ancestor, last_node);
+
+            // The block's content moves into a new copy of the formatting element.
+            let element = self.copy_element(formatting_element);
+            self.add_element_detached(element);
+            let copy = self.nodes.len() - 1;
+            self.nodes[copy].span = self.nodes[formatting_element].span;
+            let children = std::mem::take(&mut self.nodes[furthest_block].children);
+            self.record_removed(furthest_block, &children);
+            for &child in &children {
+                self.nodes[child].parent = Some(copy);
+            }
+            self.nodes[copy].children = children;
+            self.append_child(furthest_block, copy);
+
+            if let Some(i) = self.formatting.iter().position(|other| *other == entry) {
+                self.formatting.remove(i);
+                if i < bookmark {
+                    bookmark -= 1;
+                }
+            }
+            self.formatting.insert(bookmark, Formatting::Element(copy));
+            self.unfinished.remove(element_position);
+            let block_position = self
+                .unfinished
+                .iter()
+                .position(|&idx| idx == furthest_block)
+                .unwrap_or(self.unfinished.len() - 1);
+            self.unfinished.insert(block_position + 1, copy);
+        }
+        true
+    }
+
+    /// The tag names of the open elements, outermost first.
+    fn open_tags(&self) -> Vec<&str> {
+        self.unfinished
+            .iter()
+            .map(|&idx| match &self.nodes[idx].node_type {
+                NodeType::Element(element) => element.tag.as_str(),
+                NodeType::Text { .. } | NodeType::Comment { .. } => "",
+            })
+            .collect()
+    }
+
+    /// Where the innermost open `name` is in `unfinished`, not looking past any of `boundaries`.
+    fn open_position(&self, name: &str, boundaries: &[&str]) -> Option<usize> {
+        let open = self.open_tags();
+        for (position, tag) in open.iter().enumerate().rev() {
+            if *tag == name {
+                return Some(position);
+            }
+            if boundaries.contains(tag) {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Add the <html>, <head> and <body> that sloppy documents leave out. `tag` is the start tag
+    /// that's about to be added, or None for text.
+    fn implicit_tags(&mut self, tag: Option<&str>) {
+        loop {
+            let open = self.open_tags();
+            match open.as_slice() {
+                [] if tag != Some("html") => self.add_element(Element::new("html"), true),
+                // Like browsers, always have a <head>, even if it ends up empty.
+                ["html"] if tag != Some("head") && !self.has_element("head") => {
+                    self.add_element(Element::new("head"), true)
+                }
+                // An explicit <head> right after <html> is the head, not the start of the body.
+                ["html"] if tag == Some("head") && !self.has_element("head") => break,
+                ["html"] if tag != Some("body") => self.add_element(Element::new("body"), true),
+                ["html", "head"] if !tag.is_some_and(|tag| HEAD_TAGS.contains(&tag)) => {
+                    self.unfinished.pop();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn has_element(&self, tag: &str) -> bool {
+        self.nodes
+            .iter()
+            .any(|node| matches!(&node.node_type, NodeType::Element(element) if element.tag == tag))
+    }
+
+    /// Close whatever the start tag `tag` implies has ended, e.g. a <li> ends the previous <li>.
+    /// Tables also get the <tbody> and <tr> they need.
+    fn close_implied(&mut self, tag: &str) {
+        if CLOSES_P.contains(&tag) || tag == "p" || tag == "table" {
+            self.close_open(
+                &["p"],
+                &[
+                    "button", "table", "td", "th", "caption", "marquee", "object",
+                ],
+            );
+        }
+        match tag {
+            "li" => self.close_open(&["li"], &["ul", "ol", "table", "td", "th"]),
+            "dt" | "dd" => self.close_open(&["dt", "dd"], &["dl", "table", "td", "th"]),
+            "option" => self.close_open(&["option"], &["select", "datalist", "optgroup"]),
+            "optgroup" => self.close_open(&["option", "optgroup"], &["select", "datalist"]),
+            "thead" | "tbody" | "tfoot" => {
+                self.close_open(&["thead", "tbody", "tfoot", "tr", "td", "th"], &["table"])
+            }
+            "tr" => {
+                self.close_open(&["tr", "td", "th"], &["table", "tbody", "thead", "tfoot"]);
+                if self.open_tags().last() == Some(&"table") {
+                    self.add_element(Element::new("tbody"), true);
+                }
+            }
+            "td" | "th" => {
+                self.close_open(&["td", "th"], &["tr", "table"]);
+                if self.open_tags().last() == Some(&"table") {
+                    self.add_element(Element::new("tbody"), true);
+                }
+                if matches!(
+                    self.open_tags().last(),
+                    Some(&("tbody" | "thead" | "tfoot"))
+                ) {
+                    self.add_element(Element::new("tr"), true);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Close the open elements named in `names` (and everything inside them), looking no further
+    /// out than the first of `boundaries`.
+    fn close_open(&mut self, names: &[&str], boundaries: &[&str]) {
+        let position = names
+            .iter()
+            .filter_map(|name| self.open_position(name, boundaries))
+            .min();
+        if let Some(position) = position {
+            self.close_to(position);
+        }
+    }
+
+    /// Add an element that isn't in the tree yet.
+    fn add_element_detached(&mut self, element: Element) {
+        self.nodes.push(Node {
+            node_type: NodeType::Element(element),
+            children: vec![],
+            parent: None,
+            span: None,
+        });
+    }
+
+    /// Add an element under the current node, `open` ones become the current node.
+    fn add_element(&mut self, element: Element, open: bool) {
+        let parent = self.unfinished.last().cloned();
+        let node = Node {
+            node_type: NodeType::Element(element),
+            children: vec![],
+            parent,
+            span: None,
+        };
+        let idx = self.nodes.len();
+        self.nodes.push(node);
+
+        if let Some(parent_idx) = parent {
+            self.nodes[parent_idx].children.push(idx);
+        }
+        self.record_added(parent, idx);
+        if open {
+            self.unfinished.push(idx);
+        }
+    }
+
+    /// Whether we're inside an <svg> or <math>, where XML style self-closing tags work.
+    fn in_foreign_content(&self) -> bool {
+        self.unfinished.iter().any(|&idx| {
+            matches!(&self.nodes[idx].node_type,
+                NodeType::Element(element) if element.tag == "svg" || element.tag == "math")
+        })
+    }
+}
+
+pub fn print_tree(nodes: &[Node], idx: usize, indent: usize) {
+    let node = &nodes[idx];
+    let indent_str = " ".repeat(indent);
+    match &node.node_type {
+        NodeType::Element(element) => {
+            let attributes: String = element
+                .attributes
+                .iter()
+                .map(|(name, value)| format!(" {}=\"{}\"", name, value))
+                .collect();
+            println!("{}<{}{}>", indent_str, element.tag, attributes)
+        }
+        NodeType::Text { text } => println!("{}{}", indent_str, text),
+        NodeType::Comment { text } => println!("{}<!--{}-->", indent_str, text),
+    }
+    for &child_idx in &node.children {
+        print_tree(nodes, child_idx, indent + 2);
+    }
+}
+
+#[test]
+fn test_tree() {
+    let html =
+        "<div><p>Hello, you have a nice... <b>world</b>!</p><p>Goodbye.</p></div>".to_string();
+    let mut parser = HTMLParser::new(html);
+    let tree = parser.parse();
+    print_tree(&tree, 0, 0);
+}
+
+#[test]
+fn test_attributes() {
+    let parse_tag = |tag: &str| match Tokenizer::new(&format!("<{}>", tag)).next() {
+        Some(Token::StartTag { element, .. }) => element,
+        token => panic!("not a start tag: {:?}", token),
+    };
+    let element =
+        parse_tag("a href=\"x y\" class=y  data-a = 'single' disabled CLASS=z title=\"a>b\"");
+    assert_eq!(element.tag, "a");
+    assert_eq!(
+        element.attributes,
+        vec![
+            ("href".to_string(), "x y".to_string()),
+            ("class".to_string(), "y".to_string()),
+            ("data-a".to_string(), "single".to_string()),
+            ("disabled".to_string(), String::new()),
+            ("title".to_string(), "a>b".to_string()),
+        ]
+    );
+    assert_eq!(
+        parse_tag("a href='?a=1&copy=2&amp;b' title=&lt;x&gt;").attributes,
+        vec![
+            ("href".to_string(), "?a=1&copy=2&b".to_string()),
+            ("title".to_string(), "<x>".to_string()),
+        ]
+    );
+    assert_eq!(
+        outline("<p>&lt;b&gt; &amp;amp;<title>&amp;</title>"),
+        "html(head,body(p(<b> &amp;,title(&))))"
+    );
+    assert_eq!(parse_tag("BR/").tag, "br");
+    assert_eq!(parse_tag("img src=a.png /").attribute("src"), Some("a.png"));
+
+    // A quoted '>' doesn't end the tag early.
+    let nodes = HTMLParser::new("<p class=\"x\" title='1>0'>Hi</p>".to_string()).parse();
+    let p = nodes
+        .iter()
+        .find_map(|node| match &node.node_type {
+            NodeType::Element(element) if element.tag == "p" => Some(element),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(p.attribute("title"), Some("1>0"));
+    assert_eq!(
+        outline("<p class=\"x\" title='1>0'>Hi</p>"),
+        "html(head,body(p(Hi)))"
+    );
+}
+
+/// The shape of the tree as `tag(child,child)`, text shows up as itself.
+#[cfg(test)]
+fn outline(html: &str) -> String {
+    fn node(nodes: &[Node], idx: usize) -> String {
+        let children: Vec<String> = nodes[idx]
+            .children
+            .iter()
+            .map(|&c| node(nodes, c))
+            .collect();
+        match &nodes[idx].node_type {
+            NodeType::Element(element) if children.is_empty() => element.tag.clone(),
+            NodeType::Element(element) => format!("{}({})", element.tag, children.join(",")),
+            NodeType::Text { text } => text.clone(),
+            NodeType::Comment { text } => format!("<!--{}-->", text),
+        }
+    }
+    let nodes = HTMLParser::new(html.to_string()).parse();
+    let roots: Vec<String> = (0..nodes.len())
+        .filter(|&idx| nodes[idx].parent.is_none())
+        .map(|idx| node(&nodes, idx))
+        .collect();
+    roots.join(",")
+}
+
+#[test]
+fn test_void_elements() {
+    assert_eq!(
+        outline("<p>a<br>b<img src=x.png><br/>c</img></p>"),
+        "html(head,body(p(a,br,b,img,br,c)))"
+    );
+    // </br> is a <br>, and <div/> still opens a div.
+    assert_eq!(outline("<div/>x</br></div>"), "html(head,body(div(x,br)))");
+    assert_eq!(
+        outline("<svg><circle r=1 /><rect/></svg>"),
+        "html(head,body(svg(circle,rect)))"
+    );
+}
+
+#[test]
+fn test_implicit_tags() {
+    assert_eq!(outline(""), "html(head,body)");
+    assert_eq!(
+        outline("Hello <b>world</b>"),
+        "html(head,body(Hello ,b(world)))"
+    );
+    // Whitespace before the body is only kept in and after the <head>.
+    assert_eq!(
+        outline(" <html> <head>\t<title>T</title></head>\n<p>a</p> "),
+        "html(head(\t,title(T)),\n,body(p(a), ))"
+    );
+    assert_eq!(
+        outline("<title>T</title><meta charset=utf-8>text"),
+        "html(head(title(T),meta),body(text))"
+    );
+    assert_eq!(
+        outline("<p>one<p>two<div>three</div>"),
+        "html(head,body(p(one),p(two),div(three)))"
+    );
+    assert_eq!(
+        outline("<ul><li>a<li>b<ul><li>c</ul><li>d</ul>"),
+        "html(head,body(ul(li(a),li(b,ul(li(c))),li(d))))"
+    );
+    assert_eq!(
+        outline("<dl><dt>t<dd>d<dt>u</dl>"),
+        "html(head,body(dl(dt(t),dd(d),dt(u))))"
+    );
+    assert_eq!(
+        outline("<select><option>a<option>b</select>"),
+        "html(head,body(select(option(a),option(b))))"
+    );
+    assert_eq!(
+        outline("<table><tr><td>1<td>2<tr><th>3</table>"),
+        "html(head,body(table(tbody(tr(td(1),td(2)),tr(th(3))))))"
+    );
+    assert_eq!(
+        outline("<table><td>x</table>after</body>still body"),
+        "html(head,body(table(tbody(tr(td(x)))),afterstill body))"
+    );
+    // Unmatched end tags are dropped, except </p>.
+    assert_eq!(outline("<b>x</i></b></p>"), "html(head,body(b(x),p))");
+}
+
+#[test]
+fn test_comments_and_doctype() {
+    assert_eq!(
+        outline("<!-- a > b --><p>x<!--y--!>z</p><?php echo 1 ?></ 3>"),
+        "<!-- a > b -->,html(head,body(p(x,<!--y-->,z),<!--?php echo 1 ?-->,<!-- 3-->))"
+    );
+    assert_eq!(
+        outline("<!--><!--->a < b <3 </>c<!-- never closed"),
+        "<!---->,<!---->,html(head,body(a < b <3 c,<!-- never closed-->))"
+    );
+
+    let parse = |html: &str| {
+        let mut parser = HTMLParser::new(html.to_string());
+        parser.parse();
+        (parser, outline(html))
+    };
+    let (parser, tree) = parse("<!DOCTYPE html><p>hi");
+    assert_eq!(tree, "html(head,body(p(hi)))");
+    assert_eq!(
+        parser.doctype.as_ref().unwrap().name.as_deref(),
+        Some("html")
+    );
+    assert_eq!(parser.quirks_mode(), QuirksMode::NoQuirks);
+    assert_eq!(parse("<p>hi").0.quirks_mode(), QuirksMode::Quirks);
+    // A doctype after the document has started doesn't count.
+    assert_eq!(parse("<p><!doctype html>").0.doctype, None);
+
+    let quirks = |doctype: &str| match Tokenizer::new(&format!("<!DOCTYPE{}>", doctype)).next() {
+        Some(Token::Doctype(doctype)) => doctype.quirks_mode(),
+        token => panic!("not a doctype: {:?}", token),
+    };
+    assert_eq!(
+        quirks(" HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\""),
+        QuirksMode::Quirks
+    );
+    assert_eq!(
+        quirks(
+            " html PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \"http://www.w3.org/TR/html4/loose.dtd\""
+        ),
+        QuirksMode::LimitedQuirks
+    );
+    assert_eq!(
+        quirks(
+            " html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" 'http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd'"
+        ),
+        QuirksMode::NoQuirks
+    );
+    assert_eq!(
+        quirks(" html SYSTEM \"about:legacy-compat\""),
+        QuirksMode::NoQuirks
+    );
+    assert_eq!(quirks(""), QuirksMode::Quirks);
+    assert_eq!(quirks(" html PUBLIC \"unclosed"), QuirksMode::Quirks);
+}
+
+#[test]
+fn test_raw_text() {
+    assert_eq!(
+        outline("<script>if (a < b && c > d) { x = '</p>'; }</script><p>after"),
+        "html(head(script(if (a < b && c > d) { x = '</p>'; })),body(p(after)))"
+    );
+    assert_eq!(
+        outline("<style>p > b { color: red }</STYLE ><title>a <b> c</title>"),
+        "html(head(style(p > b { color: red }),title(a <b> c)),body)"
+    );
+    assert_eq!(
+        outline("<textarea>  <p>keep</p>\n</textarea>x"),
+        "html(head,body(textarea(  <p>keep</p>\n),x))"
+    );
+    // Only the first newline in a <pre> is dropped.
+    assert_eq!(
+        outline("<pre>\n\nx</pre><textarea>\ny</textarea>"),
+        "html(head,body(pre(\nx),textarea(y)))"
+    );
+    // `</scripts>` isn't the end tag, and an unclosed script runs to the end.
+    assert_eq!(
+        outline("<script>a</scripts>b"),
+        "html(head(script(a</scripts>b)),body)"
+    );
+}
+
+#[test]
+fn test_misnested_formatting() {
+    // End tags are matched by name, and can't close special elements like <div>.
+    assert_eq!(
+        outline("<div><span>a</div>b</span>"),
+        "html(head,body(div(span(a)),b))"
+    );
+    assert_eq!(
+        outline("<span><div>a</span>b</div>"),
+        "html(head,body(span(div(ab))))"
+    );
+    assert_eq!(
+        outline("<b><i>a</b>b</i>c"),
+        "html(head,body(b(i(a)),i(b),c))"
+    );
+    // Formatting closed by a paragraph carries on into the next one.
+    assert_eq!(
+        outline("<p><b>bold<p>still bold</b> plain"),
+        "html(head,body(p(b(bold)),p(b(still bold), plain)))"
+    );
+    // The adoption agency, html5lib's adoption01.dat.
+    assert_eq!(outline("<a><p></a></p>"), "html(head,body(a,p(a)))");
+    assert_eq!(
+        outline("<a>1<p>2</a>3</p>"),
+        "html(head,body(a(1),p(a(2),3)))"
+    );
+    assert_eq!(
+        outline("<a>1<button>2</a>3</button>"),
+        "html(head,body(a(1),button(a(2),3)))"
+    );
+    assert_eq!(
+        outline("<a>1<div>2<div>3</a>4</div>5</div>"),
+        "html(head,body(a(1),div(a(2),div(a(3),4),5)))"
+    );
+    assert_eq!(
+        outline("<b><a><b><p></a>"),
+        "html(head,body(b(a(b),b(p(a)))))"
+    );
+    assert_eq!(
+        outline("<div><a><b><div><div><div><div><div><div><div><div><div><div></a>"),
+        "html(head,body(div(a(b),b(div(a,div(a,div(a,div(a,div(a,div(a,div(a,div(a(div(div))))))))))))))"
+    );
+    // Links don't nest, and only three identical formatting elements get reopened.
+    assert_eq!(outline("<a>1<a>2</a>3"), "html(head,body(a(1),a(2),3))");
+    assert_eq!(
+        outline("<p><b><b><b><b>x<p>y"),
+        "html(head,body(p(b(b(b(b(x))))),p(b(b(b(y))))))"
+    );
+}
+
+#[test]
+fn test_parse_errors() {
+    use diagnostics::Position;
+
+    let source = "<div id=a>\r\n  <b>x</div>\n</span>&nope; &amp</p>";
+    let mut parser = HTMLParser::new(source.to_string());
+    let document = dom::Document::from_nodes(parser.parse());
+    let errors: Vec<(&str, usize, usize)> = parser
+        .errors
+        .iter()
+        .map(|error| {
+            let start = error.span.start;
+            (error.message.as_str(), start.line, start.column)
+        })
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            ("unclosed element <b>", 2, 3),
+            ("unexpected end tag </span>", 3, 1),
+            ("unknown character reference &nope;", 3, 8),
+            ("missing semicolon after &amp", 3, 15),
+            ("unexpected end tag </p>", 3, 19),
+        ]
+    );
+
+    // Elements point at their start tags, made up ones like <body> have no span.
+    let position = |line, column| Position { line, column };
+    let div = document.get_element_by_id("a").unwrap();
+    let span = document.node(div).span.unwrap();
+    assert_eq!((span.start, span.end), (position(1, 1), position(1, 11)));
+    let b = document.get_elements_by_tag_name("b")[0];
+    let text = document.children(b).next().unwrap();
+    let span = document.node(text).span.unwrap();
+    assert_eq!((span.start, span.end), (position(2, 6), position(2, 7)));
+    let body = document.parent(div).unwrap();
+    assert_eq!(document.node(body).span, None);
+
+    // Leaving things open at the end is an error too.
+    let mut parser = HTMLParser::new("<p><span>a<!-- b".to_string());
+    parser.parse();
+    let messages: Vec<&str> = parser.errors.iter().map(|e| e.message.as_str()).collect();
+    assert_eq!(
+        messages,
+        ["end of file in comment", "unclosed element <span>"]
+    );
+}
+
+#[test]
+fn test_streaming() {
+    let mut source = "<!DOCTYPE html>\r\n<meta charset=utf-8><title>caf\u{e9} &amp; co</title>\r\n\
+        <pre>\r\nfirst line</pre><p><b>bold<i>both</b>italic</i>\r\n<script>if (a </scr) {}</script>"
+        .to_string();
+    for i in 0..100 {
+        source.push_str(&format!("<p>paragraph {} &mdash; \u{1F600}\r\n", i));
+    }
+
+    let mut streaming = HTMLParser::streaming(Some("text/html"));
+    let mut records = Vec::new();
+    let half = source.len() / 2;
+    for chunk in source.as_bytes()[..half].chunks(5) {
+        records.extend(streaming.feed(chunk));
+    }
+    // Half way through, the first half of the page is already there to show.
+    let nodes = streaming.nodes().len();
+    streaming.with_document(|partial| {
+        assert!(partial.doctype.is_some());
+        let body = partial.get_elements_by_tag_name("body")[0];
+        assert!(partial.text_content(body).contains("paragraph 20 \u{2014}"));
+        // The <html> itself goes in at the top level.
+        assert!(records.iter().any(|record| matches!(
+            record,
+            MutationRecord::ChildList { target: None, added, .. } if added.first() == partial.roots().next().as_ref()
+        )));
+        assert!(records.iter().any(|record| matches!(
+            record,
+            MutationRecord::ChildList { target, .. } if *target == Some(body)
+        )));
+    });
+    // Lending the tree out leaves the parser as it was.
+    assert_eq!(streaming.nodes().len(), nodes);
+    for chunk in source.as_bytes()[half..].chunks(5) {
+        streaming.feed(chunk);
+    }
+    streaming.finish();
+
+    // It all comes out the same as parsing the page in one go, however it's split up.
+    let mut whole = HTMLParser::new(source.clone());
+    let expected = dom::Document::from_nodes(whole.parse()).to_html();
+    for size in [1, 2, 3, 1000] {
+        let mut parser = HTMLParser::streaming(Some("text/html"));
+        for chunk in source.as_bytes().chunks(size) {
+            parser.feed(chunk);
+        }
+        parser.finish();
+        let html = dom::Document::from_nodes(parser.nodes().to_vec()).to_html();
+        assert_eq!(html, expected, "in chunks of {}", size);
+        assert_eq!(parser.errors, whole.errors);
+    }
+    assert_eq!(
+        dom::Document::from_nodes(streaming.nodes().to_vec()).to_html(),
+        expected
+    );
+    assert_eq!(streaming.doctype, whole.doctype);
+}
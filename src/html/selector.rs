@@ -0,0 +1,298 @@
+/*
+    CSS selectors, enough of https://drafts.csswg.org/selectors/ for `query_selector`: type and
+    universal selectors, #ids, .classes, [attribute] tests with = ~= |= ^= $= *=, the descendant,
+    child (>), next sibling (+) and subsequent sibling (~) combinators, and comma separated lists.
+    No pseudo-classes or pseudo-elements.
+
+    A complex selector like `ul > li a` is kept as its compound parts in order, and matched right
+    to left: the element has to match `a`, then one of its ancestors `li`, whose parent is a `ul`.
+*/
+
+use super::dom::{Document, NodeId};
+use crate::layout::text::Element;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A parsed selector list. Matches an element if any of its selectors do.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector(Vec<Vec<(Combinator, Compound)>>);
+
+/// How a compound selector relates to the one before it. The first one's is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Combinator {
+    Descendant,
+    Child,
+    NextSibling,
+    SubsequentSibling,
+}
+
+/// Everything that has to be true of a single element, e.g. `li.done[title]`.
+#[derive(Debug, Clone, PartialEq, Default)]
+struct Compound {
+    /// None for `*` or no type at all.
+    tag: Option<String>,
+    conditions: Vec<Condition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    Id(String),
+    Class(String),
+    /// `[name]`, or `[name op value]` when there's a comparison.
+    Attribute {
+        name: String,
+        comparison: Option<(char, String)>,
+    },
+}
+
+impl Selector {
+    pub fn parse(selector: &str) -> Result<Self, String> {
+        let mut chars = selector.chars().peekable();
+        let mut list = Vec::new();
+        loop {
+            list.push(complex(&mut chars).map_err(|e| format!("{} in {:?}", e, selector))?);
+            match chars.next() {
+                Some(',') => {}
+                None => return Ok(Selector(list)),
+                Some(c) => return Err(format!("Unexpected {:?} in {:?}", c, selector)),
+            }
+        }
+    }
+
+    pub fn matches(&self, document: &Document, id: NodeId) -> bool {
+        self.0
+            .iter()
+            .any(|complex| matches_complex(complex, document, id))
+    }
+}
+
+/// Whether `id` matches the last of `parts`, with the ones before it matched further out.
+fn matches_complex(parts: &[(Combinator, Compound)], document: &Document, id: NodeId) -> bool {
+    let Some(((combinator, compound), rest)) = parts.split_last() else {
+        return true;
+    };
+    let Some(element) = document.element(id) else {
+        return false;
+    };
+    if !compound.matches(element) {
+        return false;
+    }
+    if rest.is_empty() {
+        return true;
+    }
+    let is_element = |&id: &NodeId| document.element(id).is_some();
+    match combinator {
+        Combinator::Descendant => document
+            .ancestors(id)
+            .any(|ancestor| matches_complex(rest, document, ancestor)),
+        Combinator::Child => document
+            .parent(id)
+            .is_some_and(|parent| matches_complex(rest, document, parent)),
+        Combinator::NextSibling => document
+            .preceding_siblings(id)
+            .find(is_element)
+            .is_some_and(|sibling| matches_complex(rest, document, sibling)),
+        Combinator::SubsequentSibling => document
+            .preceding_siblings(id)
+            .filter(is_element)
+            .any(|sibling| matches_complex(rest, document, sibling)),
+    }
+}
+
+impl Compound {
+    fn matches(&self, element: &Element) -> bool {
+        if self.tag.as_ref().is_some_and(|tag| *tag != element.tag) {
+            return false;
+        }
+        self.conditions.iter().all(|condition| match condition {
+            Condition::Id(id) => element.attribute("id") == Some(id.as_str()),
+            Condition::Class(class) => element
+                .attribute("class")
+                .is_some_and(|classes| classes.split_ascii_whitespace().any(|c| c == class)),
+            Condition::Attribute { name, comparison } => {
+                let Some(value) = element.attribute(name) else {
+                    return false;
+                };
+                let Some((op, expected)) = comparison else {
+                    return true;
+                };
+                let expected = expected.as_str();
+                match op {
+                    '=' => value == expected,
+                    '~' => value.split_ascii_whitespace().any(|word| word == expected),
+                    '|' => {
+                        value == expected
+                            || value
+                                .strip_prefix(expected)
+                                .is_some_and(|rest| rest.starts_with('-'))
+                    }
+                    // An empty string never matches these three.
+                    '^' => !expected.is_empty() && value.starts_with(expected),
+                    '$' => !expected.is_empty() && value.ends_with(expected),
+                    '*' => !expected.is_empty() && value.contains(expected),
+                    _ => unreachable!("only valid comparisons get parsed"),
+                }
+            }
+        })
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) -> bool {
+    let mut skipped = false;
+    while chars.next_if(|c| c.is_ascii_whitespace()).is_some() {
+        skipped = true;
+    }
+    skipped
+}
+
+/// Compound selectors joined by combinators, up to a comma or the end.
+fn complex(chars: &mut Peekable<Chars>) -> Result<Vec<(Combinator, Compound)>, String> {
+    skip_whitespace(chars);
+    let mut parts = vec![(Combinator::Descendant, compound(chars)?)];
+    loop {
+        let whitespace = skip_whitespace(chars);
+        let combinator = match chars.peek() {
+            None | Some(',') => return Ok(parts),
+            Some('>') => Combinator::Child,
+            Some('+') => Combinator::NextSibling,
+            Some('~') => Combinator::SubsequentSibling,
+            Some(_) if whitespace => {
+                parts.push((Combinator::Descendant, compound(chars)?));
+                continue;
+            }
+            Some(&c) => return Err(format!("Unexpected {:?}", c)),
+        };
+        chars.next();
+        skip_whitespace(chars);
+        parts.push((combinator, compound(chars)?));
+    }
+}
+
+fn compound(chars: &mut Peekable<Chars>) -> Result<Compound, String> {
+    let mut compound = Compound::default();
+    let mut empty = true;
+    if chars.next_if_eq(&'*').is_some() {
+        empty = false;
+    } else if chars.peek().is_some_and(|&c| is_name_char(c)) {
+        compound.tag = Some(name(chars)?.to_ascii_lowercase());
+        empty = false;
+    }
+    loop {
+        let condition = match chars.peek() {
+            Some('#') => {
+                chars.next();
+                Condition::Id(name(chars)?)
+            }
+            Some('.') => {
+                chars.next();
+                Condition::Class(name(chars)?)
+            }
+            Some('[') => {
+                chars.next();
+                attribute(chars)?
+            }
+            _ if empty => return Err("Expected a selector".to_string()),
+            _ => return Ok(compound),
+        };
+        compound.conditions.push(condition);
+        empty = false;
+    }
+}
+
+/// The inside of `[...]`, after the `[`.
+fn attribute(chars: &mut Peekable<Chars>) -> Result<Condition, String> {
+    skip_whitespace(chars);
+    let attribute = name(chars)?.to_ascii_lowercase();
+    skip_whitespace(chars);
+    let op = match chars.next() {
+        Some(']') => {
+            return Ok(Condition::Attribute {
+                name: attribute,
+                comparison: None,
+            });
+        }
+        Some('=') => '=',
+        Some(op @ ('~' | '|' | '^' | '$' | '*')) if chars.next_if_eq(&'=').is_some() => op,
+        Some(c) => return Err(format!("Unexpected {:?} in attribute selector", c)),
+        None => return Err("Unclosed attribute selector".to_string()),
+    };
+    skip_whitespace(chars);
+    let value = match chars.peek() {
+        Some(&quote @ ('"' | '\'')) => {
+            chars.next();
+            let mut value = String::new();
+            loop {
+                match chars.next() {
+                    Some(c) if c == quote => break value,
+                    Some(c) => value.push(c),
+                    None => return Err("Unclosed string".to_string()),
+                }
+            }
+        }
+        _ => name(chars)?,
+    };
+    skip_whitespace(chars);
+    match chars.next() {
+        Some(']') => Ok(Condition::Attribute {
+            name: attribute,
+            comparison: Some((op, value)),
+        }),
+        _ => Err("Unclosed attribute selector".to_string()),
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_' || !c.is_ascii()
+}
+
+/// An identifier: a tag, id, class or attribute name. Backslash escapes a single character.
+fn name(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut name = String::new();
+    loop {
+        match chars.peek() {
+            Some('\\') => {
+                chars.next();
+                name.push(chars.next().ok_or("Selector ends in a backslash")?);
+            }
+            Some(&c) if is_name_char(c) => {
+                chars.next();
+                name.push(c);
+            }
+            _ if name.is_empty() => return Err("Expected a name".to_string()),
+            _ => return Ok(name),
+        }
+    }
+}
+
+#[test]
+fn test_query_selector() {
+    let document = Document::parse(
+        "<div id=main class='page wide'><ul><li class=done>a<li><a href=/x lang=en-GB>b</a>\
+         <li title='x y'>c</ul><p>d<p>e</div><p>f",
+    );
+    let text = |selector: &str| -> Vec<String> {
+        document
+            .query_selector_all(selector)
+            .unwrap()
+            .into_iter()
+            .map(|id| document.text_content(id))
+            .collect()
+    };
+    assert_eq!(text("li"), ["a", "b", "c"]);
+    assert_eq!(text("LI.done, li[title~=y]"), ["a", "c"]);
+    assert_eq!(text("#main > p"), ["d", "e"]);
+    assert_eq!(text("div p"), ["d", "e"]);
+    assert_eq!(text(".page.wide ul *"), ["a", "b", "b", "c"]);
+    assert_eq!(text("li + li"), ["b", "c"]);
+    assert_eq!(text("ul ~ p"), ["d", "e"]);
+    assert_eq!(text("a[href^='/'][lang|=en]"), ["b"]);
+    assert_eq!(text("body > p, div.missing"), ["f"]);
+    let first = document.query_selector("div > p").unwrap();
+    assert_eq!(
+        first.map(|id| document.text_content(id)).as_deref(),
+        Some("d")
+    );
+    assert!(document.query_selector("p:first-child").is_err());
+    assert!(document.query_selector("ul >").is_err());
+    assert!(document.query_selector("[href").is_err());
+}
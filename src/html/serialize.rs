@@ -0,0 +1,130 @@
+/*
+    Turning the tree back into HTML, following the fragment serialization algorithm:
+    https://html.spec.whatwg.org/multipage/parsing.html#serialising-html-fragments
+
+    Only what has to be escaped is: `&`, no-break spaces, `<` and `>`, plus `"` in attribute
+    values, which are always double quoted. Text in <script>, <style> etc. is written
+    out as is, since the parser never decodes anything there either.
+*/
+
+use super::dom::{Document, NodeId};
+use super::{RAW_TEXT_ELEMENTS, VOID_ELEMENTS};
+use crate::layout::text::NodeType;
+
+/// Old void elements the parser doesn't know about any more, but which still never get an end
+/// tag when serialized.
+const LEGACY_VOID_ELEMENTS: [&str; 4] = ["basefont", "bgsound", "frame", "keygen"];
+
+impl Document {
+    /// The node itself as HTML, like `outerHTML`.
+    pub fn outer_html(&self, id: NodeId) -> String {
+        let mut html = String::new();
+        self.serialize_node(id, &mut html);
+        html
+    }
+
+    /// Everything inside the node as HTML, like `innerHTML`.
+    pub fn inner_html(&self, id: NodeId) -> String {
+        let mut html = String::new();
+        self.serialize_children(id, &mut html);
+        html
+    }
+
+    /// The whole document, doctype included.
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        if let Some(doctype) = &self.doctype {
+            html.push_str(&format!(
+                "<!DOCTYPE {}>",
+                doctype.name.as_deref().unwrap_or_default()
+            ));
+        }
+        for root in self.roots() {
+            self.serialize_node(root, &mut html);
+        }
+        html
+    }
+
+    fn serialize_children(&self, id: NodeId, html: &mut String) {
+        for child in self.children(id) {
+            self.serialize_node(child, html);
+        }
+    }
+
+    fn serialize_node(&self, id: NodeId, html: &mut String) {
+        match &self.node(id).node_type {
+            NodeType::Element(element) => {
+                html.push('<');
+                html.push_str(&element.tag);
+                for (name, value) in &element.attributes {
+                    html.push_str(&format!(" {}=\"{}\"", name, escape(value, true)));
+                }
+                html.push('>');
+                let tag = element.tag.as_str();
+                if VOID_ELEMENTS.contains(&tag) || LEGACY_VOID_ELEMENTS.contains(&tag) {
+                    return;
+                }
+                self.serialize_children(id, html);
+                html.push_str(&format!("</{}>", tag));
+            }
+            NodeType::Text { text } => {
+                let raw = self
+                    .parent(id)
+                    .and_then(|parent| self.element(parent))
+                    .is_some_and(|parent| {
+                        RAW_TEXT_ELEMENTS.contains(&parent.tag.as_str())
+                            || parent.tag == "plaintext"
+                    });
+                if raw {
+                    html.push_str(text);
+                } else {
+                    html.push_str(&escape(text, false));
+                }
+            }
+            NodeType::Comment { text } => html.push_str(&format!("<!--{}-->", text)),
+        }
+    }
+}
+
+/// Escape text, or an attribute value if `in_attribute`.
+fn escape(text: &str, in_attribute: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '\u{a0}' => escaped.push_str("&nbsp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' if in_attribute => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_serialize() {
+    let html = "<!DOCTYPE html><html><head><title>a &amp; b</title></head><body>\
+        <p class=\"x\" title='say \"hi\" &amp; <go>'>1 &lt; 2&nbsp;<br><img src=a.png></p>\
+        <script>if (a < b && c) {}</script><!-- note --><textarea>&lt;/textarea&gt;</textarea>\
+        </body></html>";
+    let document = Document::parse(html);
+    assert_eq!(
+        document.to_html(),
+        "<!DOCTYPE html><html><head><title>a &amp; b</title></head><body>\
+        <p class=\"x\" title=\"say &quot;hi&quot; &amp; &lt;go&gt;\">1 &lt; 2&nbsp;<br><img src=\"a.png\"></p>\
+        <script>if (a < b && c) {}</script><!-- note --><textarea>&lt;/textarea&gt;</textarea>\
+        </body></html>"
+    );
+    let p = document.query_selector("p").unwrap().unwrap();
+    assert_eq!(
+        document.inner_html(p),
+        "1 &lt; 2&nbsp;<br><img src=\"a.png\">"
+    );
+    let title = document.get_elements_by_tag_name("title")[0];
+    assert_eq!(document.outer_html(title), "<title>a &amp; b</title>");
+
+    // Serializing and parsing again gives back the same tree.
+    let again = Document::parse(&document.to_html());
+    assert_eq!(again.to_html(), document.to_html());
+}
@@ -0,0 +1,1488 @@
+/*
+    The HTML tokenizer, https://html.spec.whatwg.org/multipage/parsing.html#tokenization.
+    A state machine that turns source text into start tags, end tags, comments, doctypes and runs
+    of text. It follows the spec state by state, so a `>` inside a quoted attribute value, a
+    comment, a CDATA section or a script ends up wherever a browser would put it.
+
+    What counts as markup depends on where the parser is: inside <script>, <style> or <textarea>
+    a `<` is (mostly) just text. The tokenizer can't know that on its own, so whoever is reading
+    the tokens switches it to the right state after those start tags, see `content_state`.
+
+    Source can also be fed in as it arrives. The tokenizer stops a little short of the end of
+    what it has until `finish`, so that no state ever sees the end of a chunk as the end of
+    the input, and hands out the text it's read so far whenever it stops.
+*/
+
+use super::diagnostics::{LineIndex, ParseError, Span};
+use super::{Doctype, RAW_TEXT_ELEMENTS, RCDATA_ELEMENTS, entities};
+use crate::layout::text::Element;
+use std::collections::VecDeque;
+use std::mem;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    StartTag {
+        element: Element,
+        self_closing: bool,
+    },
+    EndTag {
+        name: String,
+    },
+    Comment(String),
+    Doctype(Doctype),
+    /// A run of text, with character references already decoded.
+    Text(String),
+}
+
+/// The tokenizer states, named as in the spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Data,
+    RcData,
+    RawText,
+    ScriptData,
+    PlainText,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    RcDataLessThanSign,
+    RcDataEndTagOpen,
+    RcDataEndTagName,
+    RawTextLessThanSign,
+    RawTextEndTagOpen,
+    RawTextEndTagName,
+    ScriptDataLessThanSign,
+    ScriptDataEndTagOpen,
+    ScriptDataEndTagName,
+    ScriptDataEscapeStart,
+    ScriptDataEscapeStartDash,
+    ScriptDataEscaped,
+    ScriptDataEscapedDash,
+    ScriptDataEscapedDashDash,
+    ScriptDataEscapedLessThanSign,
+    ScriptDataEscapedEndTagOpen,
+    ScriptDataEscapedEndTagName,
+    ScriptDataDoubleEscapeStart,
+    ScriptDataDoubleEscaped,
+    ScriptDataDoubleEscapedDash,
+    ScriptDataDoubleEscapedDashDash,
+    ScriptDataDoubleEscapedLessThanSign,
+    ScriptDataDoubleEscapeEnd,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    /// The double and single quoted states, told apart by their quote.
+    AttributeValueQuoted(char),
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    BogusComment,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentLessThanSign,
+    CommentLessThanSignBang,
+    CommentLessThanSignBangDash,
+    CommentLessThanSignBangDashDash,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    Doctype,
+    BeforeDoctypeName,
+    DoctypeName,
+    AfterDoctypeName,
+    AfterDoctypePublicKeyword,
+    BeforeDoctypePublicIdentifier,
+    DoctypePublicIdentifierQuoted(char),
+    AfterDoctypePublicIdentifier,
+    BetweenDoctypePublicAndSystemIdentifiers,
+    AfterDoctypeSystemKeyword,
+    BeforeDoctypeSystemIdentifier,
+    DoctypeSystemIdentifierQuoted(char),
+    AfterDoctypeSystemIdentifier,
+    BogusDoctype,
+    CdataSection,
+    CdataSectionBracket,
+    CdataSectionEnd,
+}
+
+/// The state an element's content has to be tokenized in, for the elements whose content isn't
+/// markup. None means carry on as normal.
+pub fn content_state(tag: &str) -> Option<State> {
+    match tag {
+        "script" => Some(State::ScriptData),
+        "plaintext" => Some(State::PlainText),
+        _ if RAW_TEXT_ELEMENTS.contains(&tag) => Some(State::RawText),
+        _ if RCDATA_ELEMENTS.contains(&tag) => Some(State::RcData),
+        _ => None,
+    }
+}
+
+/// The states where characters are text, so anything that isn't starts there.
+fn is_text_state(state: State) -> bool {
+    matches!(
+        state,
+        State::Data
+            | State::RcData
+            | State::RawText
+            | State::ScriptData
+            | State::ScriptDataEscaped
+            | State::ScriptDataDoubleEscaped
+            | State::PlainText
+    )
+}
+
+/// What was left unfinished when the input ran out in `state`, if that's a parse error.
+fn unfinished(state: State) -> Option<&'static str> {
+    match state {
+        State::TagOpen
+        | State::EndTagOpen
+        | State::TagName
+        | State::BeforeAttributeName
+        | State::AttributeName
+        | State::AfterAttributeName
+        | State::BeforeAttributeValue
+        | State::AttributeValueQuoted(_)
+        | State::AttributeValueUnquoted
+        | State::AfterAttributeValueQuoted
+        | State::SelfClosingStartTag => Some("tag"),
+        State::CommentStart
+        | State::CommentStartDash
+        | State::Comment
+        | State::CommentLessThanSign
+        | State::CommentLessThanSignBang
+        | State::CommentLessThanSignBangDash
+        | State::CommentLessThanSignBangDashDash
+        | State::CommentEndDash
+        | State::CommentEnd
+        | State::CommentEndBang => Some("comment"),
+        State::Doctype
+        | State::BeforeDoctypeName
+        | State::DoctypeName
+        | State::AfterDoctypeName
+        | State::AfterDoctypePublicKeyword
+        | State::BeforeDoctypePublicIdentifier
+        | State::DoctypePublicIdentifierQuoted(_)
+        | State::AfterDoctypePublicIdentifier
+        | State::BetweenDoctypePublicAndSystemIdentifiers
+        | State::AfterDoctypeSystemKeyword
+        | State::BeforeDoctypeSystemIdentifier
+        | State::DoctypeSystemIdentifierQuoted(_)
+        | State::AfterDoctypeSystemIdentifier => Some("doctype"),
+        State::CdataSection | State::CdataSectionBracket | State::CdataSectionEnd => {
+            Some("CDATA section")
+        }
+        _ => None,
+    }
+}
+
+/// How far ahead of the end of the input the tokenizer stops until the input's finished. Enough
+/// for the longest thing any one step looks at, a named character reference like
+/// `&CounterClockwiseContourIntegral;`.
+const LOOKAHEAD: usize = 64;
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, '\t' | '\n' | '\x0C' | ' ')
+}
+
+pub struct Tokenizer {
+    /// The source, with newlines normalized.
+    input: String,
+    pos: usize,
+    state: State,
+    done: bool,
+    /// Set once there's no more input to come.
+    finished: bool,
+    /// The last input fed in ended in `\r`, so a `\n` starting the next is part of the same newline.
+    after_cr: bool,
+    tokens: VecDeque<(Token, Span)>,
+    lines: LineIndex,
+    /// Where the tag, comment etc. being read started, which is also where any text before it
+    /// ends.
+    markup_start: usize,
+    /// Where the last token ended, and so where any text after it starts.
+    token_end: usize,
+    errors: Vec<ParseError>,
+    /// Text that hasn't been emitted yet, so that runs of characters come out as one token.
+    text: String,
+    /// The name of the last start tag, end tags in RCDATA and the like only count if they match it.
+    last_start_tag: Option<String>,
+    /// Set by the parser while it's in <svg> or <math>, the only places `<![CDATA[` works.
+    pub allow_cdata: bool,
+
+    // The token being built.
+    tag_name: String,
+    end_tag: bool,
+    self_closing: bool,
+    attributes: Vec<(String, String)>,
+    attribute: Option<(String, String)>,
+    comment: String,
+    doctype: Doctype,
+    /// The spec's temporary buffer, for end tag names in raw text and `<script` in scripts.
+    buffer: String,
+}
+
+impl Tokenizer {
+    pub fn new(source: &str) -> Self {
+        let mut tokenizer = Tokenizer::streaming();
+        tokenizer.feed(source);
+        tokenizer.finish();
+        tokenizer
+    }
+
+    /// A tokenizer with no input yet, see `feed`.
+    pub fn streaming() -> Self {
+        Tokenizer {
+            lines: LineIndex::new(""),
+            input: String::new(),
+            pos: 0,
+            state: State::Data,
+            done: false,
+            finished: false,
+            after_cr: false,
+            tokens: VecDeque::new(),
+            markup_start: 0,
+            token_end: 0,
+            errors: Vec::new(),
+            text: String::new(),
+            last_start_tag: None,
+            allow_cdata: false,
+            tag_name: String::new(),
+            end_tag: false,
+            self_closing: false,
+            attributes: Vec::new(),
+            attribute: None,
+            comment: String::new(),
+            doctype: Doctype::default(),
+            buffer: String::new(),
+        }
+    }
+
+    /// Add more source on the end.
+    pub fn feed(&mut self, text: &str) {
+        let mut text = text;
+        if self.after_cr
+            && let Some(rest) = text.strip_prefix('\n')
+        {
+            text = rest;
+            self.after_cr = false;
+        }
+        if !text.is_empty() {
+            self.after_cr = text.ends_with('\r');
+        }
+        let start = self.input.len();
+        self.input
+            .push_str(&text.replace("\r\n", "\n").replace('\r', "\n"));
+        self.lines.extend(&self.input, start);
+    }
+
+    /// There's no more source coming, so the rest of it can be tokenized.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Switch states, see `content_state`.
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    /// The next token and where it was in the source. None at the end, or when it needs more
+    /// input before it can go on.
+    pub fn next_token(&mut self) -> Option<(Token, Span)> {
+        while self.tokens.is_empty() && !self.done {
+            if !self.finished && self.input.len() - self.pos < LOOKAHEAD {
+                // Whatever text there is so far can be shown already, the rest of it will follow
+                // as a separate token.
+                if is_text_state(self.state) {
+                    self.emit_text(self.pos);
+                    self.token_end = self.pos;
+                }
+                break;
+            }
+            self.step();
+        }
+        self.tokens.pop_front()
+    }
+
+    /// The parse errors found since last time, in the order they were found.
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        mem::take(&mut self.errors)
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        self.lines.span(&self.input, start, end)
+    }
+
+    /// A parse error about the source from `start` to `end`.
+    fn error(&mut self, start: usize, end: usize, message: String) {
+        let span = self.span(start, end);
+        self.errors.push(ParseError { message, span });
+    }
+
+    fn consume(&mut self) -> Option<char> {
+        let c = self.input[self.pos..].chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Switch to `state` and have it look at `c` again.
+    fn reconsume(&mut self, c: Option<char>, state: State) {
+        if let Some(c) = c {
+            self.pos -= c.len_utf8();
+        }
+        self.state = state;
+    }
+
+    /// Take `keyword` off the front of the input if it's there.
+    fn consume_keyword(&mut self, keyword: &str, ignore_case: bool) -> bool {
+        let found = self.input[self.pos..]
+            .get(..keyword.len())
+            .is_some_and(|s| {
+                if ignore_case {
+                    s.eq_ignore_ascii_case(keyword)
+                } else {
+                    s == keyword
+                }
+            });
+        if found {
+            self.pos += keyword.len();
+        }
+        found
+    }
+
+    /// Decode the character reference after a `&` that's just been consumed. If it isn't one, the
+    /// `&` is just an ampersand.
+    fn character_reference(&mut self, in_attribute: bool) -> String {
+        let start = self.pos - 1;
+        let rest = &self.input[self.pos..];
+        match entities::reference(rest, in_attribute) {
+            Some((value, length)) => {
+                let semicolon = rest[..length].ends_with(';');
+                self.pos += length;
+                if !semicolon {
+                    let reference = self.input[start..self.pos].to_string();
+                    self.error(
+                        start,
+                        self.pos,
+                        format!("missing semicolon after {}", reference),
+                    );
+                }
+                value
+            }
+            None => {
+                // A bare `&` is fine, an `&` that looks like a reference but isn't one is not.
+                let name = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len());
+                if rest.starts_with('#') {
+                    let message = "no digits in numeric character reference".to_string();
+                    self.error(start, self.pos + 1, message);
+                } else if name > 0 && rest[name..].starts_with(';') {
+                    let reference = format!("&{};", &rest[..name]);
+                    let end = start + reference.len();
+                    self.error(
+                        start,
+                        end,
+                        format!("unknown character reference {}", reference),
+                    );
+                }
+                "&".to_string()
+            }
+        }
+    }
+
+    fn emit(&mut self, token: Token) {
+        self.emit_text(self.markup_start);
+        self.tokens
+            .push_back((token, self.span(self.markup_start, self.pos)));
+        self.token_end = self.pos;
+    }
+
+    /// Emit the text read so far, which ends at `end`.
+    fn emit_text(&mut self, end: usize) {
+        if !self.text.is_empty() {
+            let span = self.span(self.token_end, end);
+            self.tokens
+                .push_back((Token::Text(mem::take(&mut self.text)), span));
+        }
+    }
+
+    fn end_of_file(&mut self) {
+        self.emit_text(self.input.len());
+        self.done = true;
+    }
+
+    fn new_tag(&mut self, end_tag: bool) {
+        self.tag_name.clear();
+        self.end_tag = end_tag;
+        self.self_closing = false;
+        self.attributes.clear();
+        self.attribute = None;
+    }
+
+    fn start_attribute(&mut self, name: &str) {
+        self.finish_attribute();
+        self.attribute = Some((name.to_string(), String::new()));
+    }
+
+    /// Duplicate attributes are dropped, the first one wins.
+    fn finish_attribute(&mut self) {
+        if let Some((name, value)) = self.attribute.take()
+            && !self.attributes.iter().any(|(other, _)| *other == name)
+        {
+            self.attributes.push((name, value));
+        }
+    }
+
+    fn attribute_name(&mut self) -> &mut String {
+        &mut self.attribute.get_or_insert_default().0
+    }
+
+    fn attribute_value(&mut self) -> &mut String {
+        &mut self.attribute.get_or_insert_default().1
+    }
+
+    fn emit_tag(&mut self) {
+        self.finish_attribute();
+        let name = mem::take(&mut self.tag_name);
+        let attributes = mem::take(&mut self.attributes);
+        // End tags can be written with attributes and `/>`, both are ignored.
+        let token = if self.end_tag {
+            Token::EndTag { name }
+        } else {
+            self.last_start_tag = Some(name.clone());
+            Token::StartTag {
+                element: Element {
+                    tag: name,
+                    attributes,
+                },
+                self_closing: self.self_closing,
+            }
+        };
+        self.emit(token);
+    }
+
+    fn emit_comment(&mut self) {
+        let comment = mem::take(&mut self.comment);
+        self.emit(Token::Comment(comment));
+    }
+
+    fn emit_doctype(&mut self, force_quirks: bool) {
+        let mut doctype = mem::take(&mut self.doctype);
+        doctype.force_quirks |= force_quirks;
+        self.emit(Token::Doctype(doctype));
+    }
+
+    /// Whether the end tag being built would close the element whose raw text we're in.
+    fn appropriate_end_tag(&self) -> bool {
+        self.last_start_tag.as_deref() == Some(self.tag_name.as_str())
+    }
+
+    /// After `</` in RCDATA, RAWTEXT or script data: either the start of an end tag or just text.
+    fn raw_end_tag_open(&mut self, c: Option<char>, name_state: State, text_state: State) {
+        match c {
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.new_tag(true);
+                self.reconsume(Some(c), name_state);
+            }
+            _ => {
+                self.text.push_str("</");
+                self.reconsume(c, text_state);
+            }
+        }
+    }
+
+    /// An end tag name in RCDATA, RAWTEXT or script data. It only counts if it's the end tag of
+    /// the current element, otherwise it was text all along.
+    fn raw_end_tag_name(&mut self, c: Option<char>, text_state: State) {
+        match c {
+            Some(c) if is_whitespace(c) && self.appropriate_end_tag() => {
+                self.state = State::BeforeAttributeName;
+            }
+            Some('/') if self.appropriate_end_tag() => self.state = State::SelfClosingStartTag,
+            Some('>') if self.appropriate_end_tag() => {
+                self.state = State::Data;
+                self.emit_tag();
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.tag_name.push(c.to_ascii_lowercase());
+                self.buffer.push(c);
+            }
+            _ => {
+                self.text.push_str("</");
+                self.text.push_str(&self.buffer);
+                self.reconsume(c, text_state);
+            }
+        }
+    }
+
+    /// `<script` and `</script` inside an escaped script switch in and out of double escaping.
+    fn double_escape(&mut self, c: Option<char>, script: State, other: State, text_state: State) {
+        match c {
+            Some(c) if is_whitespace(c) || c == '/' || c == '>' => {
+                self.state = if self.buffer == "script" {
+                    script
+                } else {
+                    other
+                };
+                self.text.push(c);
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                self.buffer.push(c.to_ascii_lowercase());
+                self.text.push(c);
+            }
+            _ => self.reconsume(c, text_state),
+        }
+    }
+
+    fn step(&mut self) {
+        if self.state == State::MarkupDeclarationOpen {
+            return self.markup_declaration_open();
+        }
+        let state = self.state;
+        if is_text_state(state) {
+            self.markup_start = self.pos;
+        }
+        let c = self.consume();
+        self.run_state(c);
+        // Only counted once the input's really finished, some states hand the end on to others.
+        if c.is_none()
+            && self.done
+            && let Some(what) = unfinished(state)
+        {
+            let message = format!("end of file in {}", what);
+            self.error(self.markup_start, self.pos, message);
+        }
+    }
+
+    fn run_state(&mut self, c: Option<char>) {
+        match self.state {
+            State::Data => match c {
+                Some('&') => {
+                    let value = self.character_reference(false);
+                    self.text.push_str(&value);
+                }
+                Some('<') => self.state = State::TagOpen,
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::RcData => match c {
+                Some('&') => {
+                    let value = self.character_reference(false);
+                    self.text.push_str(&value);
+                }
+                Some('<') => self.state = State::RcDataLessThanSign,
+                Some('\0') => self.text.push('\u{FFFD}'),
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::RawText => match c {
+                Some('<') => self.state = State::RawTextLessThanSign,
+                Some('\0') => self.text.push('\u{FFFD}'),
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::ScriptData => match c {
+                Some('<') => self.state = State::ScriptDataLessThanSign,
+                Some('\0') => self.text.push('\u{FFFD}'),
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::PlainText => match c {
+                Some('\0') => self.text.push('\u{FFFD}'),
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::TagOpen => match c {
+                Some('!') => self.state = State::MarkupDeclarationOpen,
+                Some('/') => self.state = State::EndTagOpen,
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(false);
+                    self.reconsume(Some(c), State::TagName);
+                }
+                Some('?') => {
+                    self.comment.clear();
+                    self.reconsume(c, State::BogusComment);
+                }
+                None => {
+                    self.text.push('<');
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.text.push('<');
+                    self.reconsume(c, State::Data);
+                }
+            },
+            State::EndTagOpen => match c {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.new_tag(true);
+                    self.reconsume(Some(c), State::TagName);
+                }
+                // `</>` is dropped altogether.
+                Some('>') => self.state = State::Data,
+                None => {
+                    self.text.push_str("</");
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.comment.clear();
+                    self.reconsume(c, State::BogusComment);
+                }
+            },
+            State::TagName => match c {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Some('\0') => self.tag_name.push('\u{FFFD}'),
+                Some(c) => self.tag_name.push(c.to_ascii_lowercase()),
+                // A tag still open at the end of the file is dropped.
+                None => self.end_of_file(),
+            },
+
+            State::RcDataLessThanSign => match c {
+                Some('/') => {
+                    self.buffer.clear();
+                    self.state = State::RcDataEndTagOpen;
+                }
+                _ => {
+                    self.text.push('<');
+                    self.reconsume(c, State::RcData);
+                }
+            },
+            State::RcDataEndTagOpen => {
+                self.raw_end_tag_open(c, State::RcDataEndTagName, State::RcData)
+            }
+            State::RcDataEndTagName => self.raw_end_tag_name(c, State::RcData),
+            State::RawTextLessThanSign => match c {
+                Some('/') => {
+                    self.buffer.clear();
+                    self.state = State::RawTextEndTagOpen;
+                }
+                _ => {
+                    self.text.push('<');
+                    self.reconsume(c, State::RawText);
+                }
+            },
+            State::RawTextEndTagOpen => {
+                self.raw_end_tag_open(c, State::RawTextEndTagName, State::RawText)
+            }
+            State::RawTextEndTagName => self.raw_end_tag_name(c, State::RawText),
+
+            // Scripts have their own rules, so that `<!-- document.write("<script></script>") -->`
+            // doesn't end the script early.
+            State::ScriptDataLessThanSign => match c {
+                Some('/') => {
+                    self.buffer.clear();
+                    self.state = State::ScriptDataEndTagOpen;
+                }
+                Some('!') => {
+                    self.text.push_str("<!");
+                    self.state = State::ScriptDataEscapeStart;
+                }
+                _ => {
+                    self.text.push('<');
+                    self.reconsume(c, State::ScriptData);
+                }
+            },
+            State::ScriptDataEndTagOpen => {
+                self.raw_end_tag_open(c, State::ScriptDataEndTagName, State::ScriptData)
+            }
+            State::ScriptDataEndTagName => self.raw_end_tag_name(c, State::ScriptData),
+            State::ScriptDataEscapeStart => match c {
+                Some('-') => {
+                    self.text.push('-');
+                    self.state = State::ScriptDataEscapeStartDash;
+                }
+                _ => self.reconsume(c, State::ScriptData),
+            },
+            State::ScriptDataEscapeStartDash => match c {
+                Some('-') => {
+                    self.text.push('-');
+                    self.state = State::ScriptDataEscapedDashDash;
+                }
+                _ => self.reconsume(c, State::ScriptData),
+            },
+            State::ScriptDataEscaped => match c {
+                Some('-') => {
+                    self.text.push('-');
+                    self.state = State::ScriptDataEscapedDash;
+                }
+                Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+                Some('\0') => self.text.push('\u{FFFD}'),
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::ScriptDataEscapedDash => match c {
+                Some('-') => {
+                    self.text.push('-');
+                    self.state = State::ScriptDataEscapedDashDash;
+                }
+                Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+                Some('\0') => {
+                    self.text.push('\u{FFFD}');
+                    self.state = State::ScriptDataEscaped;
+                }
+                Some(c) => {
+                    self.text.push(c);
+                    self.state = State::ScriptDataEscaped;
+                }
+                None => self.end_of_file(),
+            },
+            State::ScriptDataEscapedDashDash => match c {
+                Some('-') => self.text.push('-'),
+                Some('<') => self.state = State::ScriptDataEscapedLessThanSign,
+                Some('>') => {
+                    self.text.push('>');
+                    self.state = State::ScriptData;
+                }
+                Some('\0') => {
+                    self.text.push('\u{FFFD}');
+                    self.state = State::ScriptDataEscaped;
+                }
+                Some(c) => {
+                    self.text.push(c);
+                    self.state = State::ScriptDataEscaped;
+                }
+                None => self.end_of_file(),
+            },
+            State::ScriptDataEscapedLessThanSign => match c {
+                Some('/') => {
+                    self.buffer.clear();
+                    self.state = State::ScriptDataEscapedEndTagOpen;
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.buffer.clear();
+                    self.text.push('<');
+                    self.reconsume(Some(c), State::ScriptDataDoubleEscapeStart);
+                }
+                _ => {
+                    self.text.push('<');
+                    self.reconsume(c, State::ScriptDataEscaped);
+                }
+            },
+            State::ScriptDataEscapedEndTagOpen => self.raw_end_tag_open(
+                c,
+                State::ScriptDataEscapedEndTagName,
+                State::ScriptDataEscaped,
+            ),
+            State::ScriptDataEscapedEndTagName => {
+                self.raw_end_tag_name(c, State::ScriptDataEscaped)
+            }
+            State::ScriptDataDoubleEscapeStart => self.double_escape(
+                c,
+                State::ScriptDataDoubleEscaped,
+                State::ScriptDataEscaped,
+                State::ScriptDataEscaped,
+            ),
+            State::ScriptDataDoubleEscaped => match c {
+                Some('-') => {
+                    self.text.push('-');
+                    self.state = State::ScriptDataDoubleEscapedDash;
+                }
+                Some('<') => {
+                    self.text.push('<');
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                }
+                Some('\0') => self.text.push('\u{FFFD}'),
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::ScriptDataDoubleEscapedDash => match c {
+                Some('-') => {
+                    self.text.push('-');
+                    self.state = State::ScriptDataDoubleEscapedDashDash;
+                }
+                Some('<') => {
+                    self.text.push('<');
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                }
+                Some('\0') => {
+                    self.text.push('\u{FFFD}');
+                    self.state = State::ScriptDataDoubleEscaped;
+                }
+                Some(c) => {
+                    self.text.push(c);
+                    self.state = State::ScriptDataDoubleEscaped;
+                }
+                None => self.end_of_file(),
+            },
+            State::ScriptDataDoubleEscapedDashDash => match c {
+                Some('-') => self.text.push('-'),
+                Some('<') => {
+                    self.text.push('<');
+                    self.state = State::ScriptDataDoubleEscapedLessThanSign;
+                }
+                Some('>') => {
+                    self.text.push('>');
+                    self.state = State::ScriptData;
+                }
+                Some('\0') => {
+                    self.text.push('\u{FFFD}');
+                    self.state = State::ScriptDataDoubleEscaped;
+                }
+                Some(c) => {
+                    self.text.push(c);
+                    self.state = State::ScriptDataDoubleEscaped;
+                }
+                None => self.end_of_file(),
+            },
+            State::ScriptDataDoubleEscapedLessThanSign => match c {
+                Some('/') => {
+                    self.buffer.clear();
+                    self.text.push('/');
+                    self.state = State::ScriptDataDoubleEscapeEnd;
+                }
+                _ => self.reconsume(c, State::ScriptDataDoubleEscaped),
+            },
+            State::ScriptDataDoubleEscapeEnd => self.double_escape(
+                c,
+                State::ScriptDataEscaped,
+                State::ScriptDataDoubleEscaped,
+                State::ScriptDataDoubleEscaped,
+            ),
+
+            State::BeforeAttributeName => match c {
+                Some(c) if is_whitespace(c) => {}
+                Some('/' | '>') | None => self.reconsume(c, State::AfterAttributeName),
+                // A name can start with '=', it just can't have one after that.
+                Some('=') => {
+                    self.start_attribute("=");
+                    self.state = State::AttributeName;
+                }
+                Some(_) => {
+                    self.start_attribute("");
+                    self.reconsume(c, State::AttributeName);
+                }
+            },
+            State::AttributeName => match c {
+                Some(c) if is_whitespace(c) => self.reconsume(Some(c), State::AfterAttributeName),
+                Some('/' | '>') | None => self.reconsume(c, State::AfterAttributeName),
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('\0') => self.attribute_name().push('\u{FFFD}'),
+                Some(c) => self.attribute_name().push(c.to_ascii_lowercase()),
+            },
+            State::AfterAttributeName => match c {
+                Some(c) if is_whitespace(c) => {}
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                None => self.end_of_file(),
+                Some(_) => {
+                    self.start_attribute("");
+                    self.reconsume(c, State::AttributeName);
+                }
+            },
+            State::BeforeAttributeValue => match c {
+                Some(c) if is_whitespace(c) => {}
+                Some(quote @ ('"' | '\'')) => self.state = State::AttributeValueQuoted(quote),
+                // `<a href=>` has an empty value.
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                _ => self.reconsume(c, State::AttributeValueUnquoted),
+            },
+            State::AttributeValueQuoted(quote) => match c {
+                Some(c) if c == quote => self.state = State::AfterAttributeValueQuoted,
+                Some('&') => {
+                    let value = self.character_reference(true);
+                    self.attribute_value().push_str(&value);
+                }
+                Some('\0') => self.attribute_value().push('\u{FFFD}'),
+                Some(c) => self.attribute_value().push(c),
+                None => self.end_of_file(),
+            },
+            State::AttributeValueUnquoted => match c {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('&') => {
+                    let value = self.character_reference(true);
+                    self.attribute_value().push_str(&value);
+                }
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                Some('\0') => self.attribute_value().push('\u{FFFD}'),
+                Some(c) => self.attribute_value().push(c),
+                None => self.end_of_file(),
+            },
+            State::AfterAttributeValueQuoted => match c {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                None => self.end_of_file(),
+                Some(_) => self.reconsume(c, State::BeforeAttributeName),
+            },
+            State::SelfClosingStartTag => match c {
+                Some('>') => {
+                    self.self_closing = true;
+                    self.state = State::Data;
+                    self.emit_tag();
+                }
+                None => self.end_of_file(),
+                Some(_) => self.reconsume(c, State::BeforeAttributeName),
+            },
+
+            // `<?php ... ?>`, `</ 3>` and the like are comments that run to the next `>`.
+            State::BogusComment => match c {
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Some('\0') => self.comment.push('\u{FFFD}'),
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.emit_comment();
+                    self.end_of_file();
+                }
+            },
+            State::MarkupDeclarationOpen => unreachable!(),
+            State::CommentStart => match c {
+                Some('-') => self.state = State::CommentStartDash,
+                // `<!-->` is an (empty) comment.
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                _ => self.reconsume(c, State::Comment),
+            },
+            State::CommentStartDash => match c {
+                Some('-') => self.state = State::CommentEnd,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                None => {
+                    self.emit_comment();
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.comment.push('-');
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            State::Comment => match c {
+                Some('<') => {
+                    self.comment.push('<');
+                    self.state = State::CommentLessThanSign;
+                }
+                Some('-') => self.state = State::CommentEndDash,
+                Some('\0') => self.comment.push('\u{FFFD}'),
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.emit_comment();
+                    self.end_of_file();
+                }
+            },
+            State::CommentLessThanSign => match c {
+                Some('!') => {
+                    self.comment.push('!');
+                    self.state = State::CommentLessThanSignBang;
+                }
+                Some('<') => self.comment.push('<'),
+                _ => self.reconsume(c, State::Comment),
+            },
+            State::CommentLessThanSignBang => match c {
+                Some('-') => self.state = State::CommentLessThanSignBangDash,
+                _ => self.reconsume(c, State::Comment),
+            },
+            State::CommentLessThanSignBangDash => match c {
+                Some('-') => self.state = State::CommentLessThanSignBangDashDash,
+                _ => self.reconsume(c, State::CommentEndDash),
+            },
+            State::CommentLessThanSignBangDashDash => self.reconsume(c, State::CommentEnd),
+            State::CommentEndDash => match c {
+                Some('-') => self.state = State::CommentEnd,
+                None => {
+                    self.emit_comment();
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.comment.push('-');
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            State::CommentEnd => match c {
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                Some('!') => self.state = State::CommentEndBang,
+                Some('-') => self.comment.push('-'),
+                None => {
+                    self.emit_comment();
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.comment.push_str("--");
+                    self.reconsume(c, State::Comment);
+                }
+            },
+            // `--!>` ends a comment too.
+            State::CommentEndBang => match c {
+                Some('-') => {
+                    self.comment.push_str("--!");
+                    self.state = State::CommentEndDash;
+                }
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_comment();
+                }
+                None => {
+                    self.emit_comment();
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.comment.push_str("--!");
+                    self.reconsume(c, State::Comment);
+                }
+            },
+
+            State::Doctype => match c {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeDoctypeName,
+                None => {
+                    self.doctype = Doctype::default();
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+                Some(_) => self.reconsume(c, State::BeforeDoctypeName),
+            },
+            State::BeforeDoctypeName => match c {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => {
+                    self.doctype = Doctype::default();
+                    self.state = State::Data;
+                    self.emit_doctype(true);
+                }
+                None => {
+                    self.doctype = Doctype::default();
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+                Some(c) => {
+                    let c = if c == '\0' { '\u{FFFD}' } else { c };
+                    self.doctype = Doctype {
+                        name: Some(c.to_ascii_lowercase().to_string()),
+                        ..Doctype::default()
+                    };
+                    self.state = State::DoctypeName;
+                }
+            },
+            State::DoctypeName => match c {
+                Some(c) if is_whitespace(c) => self.state = State::AfterDoctypeName,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype(false);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+                Some(c) => {
+                    let c = if c == '\0' { '\u{FFFD}' } else { c };
+                    let name = self.doctype.name.get_or_insert_default();
+                    name.push(c.to_ascii_lowercase());
+                }
+            },
+            State::AfterDoctypeName => match c {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype(false);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+                Some(_) => {
+                    self.reconsume(c, State::AfterDoctypeName);
+                    if self.consume_keyword("public", true) {
+                        self.state = State::AfterDoctypePublicKeyword;
+                    } else if self.consume_keyword("system", true) {
+                        self.state = State::AfterDoctypeSystemKeyword;
+                    } else {
+                        self.doctype.force_quirks = true;
+                        self.state = State::BogusDoctype;
+                    }
+                }
+            },
+            State::AfterDoctypePublicKeyword | State::BeforeDoctypePublicIdentifier => {
+                let after_keyword = self.state == State::AfterDoctypePublicKeyword;
+                match c {
+                    Some(c) if is_whitespace(c) && after_keyword => {
+                        self.state = State::BeforeDoctypePublicIdentifier;
+                    }
+                    Some(c) if is_whitespace(c) => {}
+                    Some(quote @ ('"' | '\'')) => {
+                        self.doctype.public_id = Some(String::new());
+                        self.state = State::DoctypePublicIdentifierQuoted(quote);
+                    }
+                    _ => self.broken_doctype(c),
+                }
+            }
+            State::DoctypePublicIdentifierQuoted(quote) => match c {
+                Some(c) if c == quote => self.state = State::AfterDoctypePublicIdentifier,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype(true);
+                }
+                Some(c) => {
+                    let c = if c == '\0' { '\u{FFFD}' } else { c };
+                    self.doctype.public_id.get_or_insert_default().push(c);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+            },
+            State::AfterDoctypePublicIdentifier
+            | State::BetweenDoctypePublicAndSystemIdentifiers => {
+                let after_identifier = self.state == State::AfterDoctypePublicIdentifier;
+                match c {
+                    Some(c) if is_whitespace(c) && after_identifier => {
+                        self.state = State::BetweenDoctypePublicAndSystemIdentifiers;
+                    }
+                    Some(c) if is_whitespace(c) => {}
+                    Some('>') => {
+                        self.state = State::Data;
+                        self.emit_doctype(false);
+                    }
+                    Some(quote @ ('"' | '\'')) => {
+                        self.doctype.system_id = Some(String::new());
+                        self.state = State::DoctypeSystemIdentifierQuoted(quote);
+                    }
+                    _ => self.broken_doctype(c),
+                }
+            }
+            State::AfterDoctypeSystemKeyword | State::BeforeDoctypeSystemIdentifier => {
+                let after_keyword = self.state == State::AfterDoctypeSystemKeyword;
+                match c {
+                    Some(c) if is_whitespace(c) && after_keyword => {
+                        self.state = State::BeforeDoctypeSystemIdentifier;
+                    }
+                    Some(c) if is_whitespace(c) => {}
+                    Some(quote @ ('"' | '\'')) => {
+                        self.doctype.system_id = Some(String::new());
+                        self.state = State::DoctypeSystemIdentifierQuoted(quote);
+                    }
+                    _ => self.broken_doctype(c),
+                }
+            }
+            State::DoctypeSystemIdentifierQuoted(quote) => match c {
+                Some(c) if c == quote => self.state = State::AfterDoctypeSystemIdentifier,
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype(true);
+                }
+                Some(c) => {
+                    let c = if c == '\0' { '\u{FFFD}' } else { c };
+                    self.doctype.system_id.get_or_insert_default().push(c);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+            },
+            State::AfterDoctypeSystemIdentifier => match c {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype(false);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.end_of_file();
+                }
+                // Junk after the system id is ignored, but doesn't mean quirks mode.
+                Some(_) => self.reconsume(c, State::BogusDoctype),
+            },
+            State::BogusDoctype => match c {
+                Some('>') => {
+                    self.state = State::Data;
+                    self.emit_doctype(false);
+                }
+                None => {
+                    self.emit_doctype(false);
+                    self.end_of_file();
+                }
+                Some(_) => {}
+            },
+
+            State::CdataSection => match c {
+                Some(']') => self.state = State::CdataSectionBracket,
+                Some(c) => self.text.push(c),
+                None => self.end_of_file(),
+            },
+            State::CdataSectionBracket => match c {
+                Some(']') => self.state = State::CdataSectionEnd,
+                _ => {
+                    self.text.push(']');
+                    self.reconsume(c, State::CdataSection);
+                }
+            },
+            State::CdataSectionEnd => match c {
+                Some(']') => self.text.push(']'),
+                Some('>') => self.state = State::Data,
+                _ => {
+                    self.text.push_str("]]");
+                    self.reconsume(c, State::CdataSection);
+                }
+            },
+        }
+    }
+
+    /// Something unexpected in the middle of a doctype's identifiers, the doctype is still
+    /// emitted but can't be trusted.
+    fn broken_doctype(&mut self, c: Option<char>) {
+        match c {
+            Some('>') => {
+                self.state = State::Data;
+                self.emit_doctype(true);
+            }
+            None => {
+                self.emit_doctype(true);
+                self.end_of_file();
+            }
+            Some(_) => {
+                self.doctype.force_quirks = true;
+                self.reconsume(c, State::BogusDoctype);
+            }
+        }
+    }
+
+    /// After `<!`, which could be a comment, a doctype or a CDATA section.
+    fn markup_declaration_open(&mut self) {
+        self.comment.clear();
+        if self.consume_keyword("--", false) {
+            self.state = State::CommentStart;
+        } else if self.consume_keyword("doctype", true) {
+            self.state = State::Doctype;
+        } else if self.consume_keyword("[CDATA[", false) {
+            if self.allow_cdata {
+                self.state = State::CdataSection;
+            } else {
+                // In HTML content it's a comment, `<![CDATA[x]]>` is `<!--[CDATA[x]]-->`.
+                self.comment.push_str("[CDATA[");
+                self.state = State::BogusComment;
+            }
+        } else {
+            self.state = State::BogusComment;
+        }
+    }
+}
+
+impl Iterator for Tokenizer {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Token> {
+        self.next_token().map(|(token, _)| token)
+    }
+}
+
+#[test]
+fn test_tokenizer() {
+    let tokens = |html: &str| Tokenizer::new(html).collect::<Vec<_>>();
+    let text = |text: &str| Token::Text(text.to_string());
+
+    // `>` in attribute values and comments, and `<` that doesn't start a tag.
+    assert_eq!(
+        tokens("<a title='1>0'>x</a\n><!-- <b> -->a < b"),
+        vec![
+            Token::StartTag {
+                element: Element {
+                    tag: "a".to_string(),
+                    attributes: vec![("title".to_string(), "1>0".to_string())],
+                },
+                self_closing: false,
+            },
+            text("x"),
+            Token::EndTag {
+                name: "a".to_string()
+            },
+            Token::Comment(" <b> ".to_string()),
+            text("a < b"),
+        ]
+    );
+
+    // CDATA sections are only text in foreign content, elsewhere they're comments.
+    assert_eq!(
+        tokens("<![CDATA[a<b]]>"),
+        vec![Token::Comment("[CDATA[a<b]]".to_string())]
+    );
+    let mut tokenizer = Tokenizer::new("<![CDATA[a<b]]]>c");
+    tokenizer.allow_cdata = true;
+    assert_eq!(tokenizer.collect::<Vec<_>>(), vec![text("a<b]c")]);
+
+    // A script's `</script>` inside an escaped `<!-- <script>` doesn't end it.
+    let mut tokenizer = Tokenizer::new("<!--<script></script>--></script>");
+    tokenizer.set_state(State::ScriptData);
+    tokenizer.last_start_tag = Some("script".to_string());
+    assert_eq!(
+        tokenizer.collect::<Vec<_>>(),
+        vec![
+            text("<!--<script></script>-->"),
+            Token::EndTag {
+                name: "script".to_string()
+            }
+        ]
+    );
+}
+
+/// Runs the html5lib tokenizer tests in tests/html5lib/tokenizer. Parse errors aren't checked,
+/// only the tokens.
+#[test]
+fn test_html5lib_tokenizer() {
+    use serde_json::{Value, json};
+
+    // Tests marked doubleEscaped write some characters as `\uXXXX`.
+    fn unescape(value: &Value) -> Option<Value> {
+        Some(match value {
+            Value::String(s) => {
+                let mut unescaped = String::new();
+                let mut rest = s.as_str();
+                while let Some(start) = rest.find("\\u") {
+                    unescaped.push_str(&rest[..start]);
+                    let code = u32::from_str_radix(rest.get(start + 2..start + 6)?, 16).ok()?;
+                    // Lone surrogates can't be in a Rust string, decoding replaces them earlier.
+                    unescaped.push(char::from_u32(code)?);
+                    rest = &rest[start + 6..];
+                }
+                unescaped.push_str(rest);
+                Value::String(unescaped)
+            }
+            Value::Array(items) => Value::Array(items.iter().map(unescape).collect::<Option<_>>()?),
+            Value::Object(map) => Value::Object(
+                map.iter()
+                    .map(|(k, v)| Some((k.clone(), unescape(v)?)))
+                    .collect::<Option<_>>()?,
+            ),
+            other => other.clone(),
+        })
+    }
+
+    // Drop the parse errors and join up the text they split.
+    fn normalize(tokens: Vec<Value>) -> Vec<Value> {
+        let mut normalized: Vec<Value> = Vec::new();
+        for token in tokens.into_iter().filter(|token| token != "ParseError") {
+            if let Some(last) = normalized.last_mut()
+                && last[0] == "Character"
+                && token[0] == "Character"
+            {
+                let joined = format!(
+                    "{}{}",
+                    last[1].as_str().unwrap(),
+                    token[1].as_str().unwrap()
+                );
+                last[1] = Value::String(joined);
+                continue;
+            }
+            normalized.push(token);
+        }
+        normalized
+    }
+
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/html5lib/tokenizer");
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "test"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty());
+
+    let mut failures = Vec::new();
+    let mut count = 0;
+    for file in files {
+        let fixture: Value =
+            serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+        let Some(tests) = fixture["tests"].as_array() else {
+            continue;
+        };
+        for test in tests {
+            let (input, output) = if test["doubleEscaped"] == true {
+                match (unescape(&test["input"]), unescape(&test["output"])) {
+                    (Some(input), Some(output)) => (input, output),
+                    _ => continue,
+                }
+            } else {
+                (test["input"].clone(), test["output"].clone())
+            };
+            let expected = normalize(output.as_array().unwrap().clone());
+            let states = test["initialStates"]
+                .as_array()
+                .cloned()
+                .unwrap_or_else(|| vec![json!("Data state")]);
+            for state in states {
+                let mut tokenizer = Tokenizer::new(input.as_str().unwrap());
+                tokenizer.set_state(match state.as_str().unwrap() {
+                    "Data state" => State::Data,
+                    "PLAINTEXT state" => State::PlainText,
+                    "RCDATA state" => State::RcData,
+                    "RAWTEXT state" => State::RawText,
+                    "Script data state" => State::ScriptData,
+                    "CDATA section state" => State::CdataSection,
+                    other => panic!("unknown state {}", other),
+                });
+                tokenizer.last_start_tag = test["lastStartTag"].as_str().map(str::to_string);
+                let actual: Vec<Value> = tokenizer
+                    .map(|token| match token {
+                        Token::StartTag {
+                            element,
+                            self_closing,
+                        } => {
+                            let attributes: serde_json::Map<_, _> = element
+                                .attributes
+                                .into_iter()
+                                .map(|(name, value)| (name, Value::String(value)))
+                                .collect();
+                            if self_closing {
+                                json!(["StartTag", element.tag, attributes, true])
+                            } else {
+                                json!(["StartTag", element.tag, attributes])
+                            }
+                        }
+                        Token::EndTag { name } => json!(["EndTag", name]),
+                        Token::Comment(text) => json!(["Comment", text]),
+                        Token::Doctype(doctype) => json!([
+                            "DOCTYPE",
+                            doctype.name,
+                            doctype.public_id,
+                            doctype.system_id,
+                            !doctype.force_quirks
+                        ]),
+                        Token::Text(text) => json!(["Character", text]),
+                    })
+                    .collect();
+                count += 1;
+                if normalize(actual.clone()) != expected {
+                    failures.push(format!(
+                        "{} in {}: {}\n  expected {}\n  got      {}",
+                        test["description"],
+                        state,
+                        input,
+                        Value::Array(expected.clone()),
+                        Value::Array(actual)
+                    ));
+                }
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} html5lib tokenizer tests failed:\n{}",
+        failures.len(),
+        count,
+        failures.join("\n")
+    );
+}
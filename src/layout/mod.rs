@@ -32,7 +32,7 @@ impl Default for LayoutFont {
 impl LayoutFont {
     pub fn to_font(&self) -> font_kit::font::Font {
         SystemSource::new()
-            .select_best_match(&[self.family.clone()], &self.properties)
+            .select_best_match(std::slice::from_ref(&self.family), &self.properties)
             .unwrap()
             .load()
             .unwrap()
@@ -7,6 +7,13 @@ use crate::layout::text::{StyledText, TokenAction};
 
 use super::{LayoutFont, text::Body};
 
+const BLACK: Source = Source::Solid(SolidSource {
+    r: 0,
+    g: 0,
+    b: 0,
+    a: 0xff,
+});
+
 /// A context that can draw vector Text
 pub struct Layout {
     // The main draw target
@@ -31,8 +38,6 @@ pub struct Layout {
     height: f32,
 
     align: String,
-
-    pt: f32, // Point size
 }
 
 impl Layout {
@@ -50,12 +55,28 @@ impl Layout {
             width,
             height,
             align: "center".to_string(),
-            pt: 16.0,
         };
         s.lines();
         s
     }
 
+    /// Swap in a new document, e.g. once the page has finished loading.
+    pub fn set_body(&mut self, body: Body) {
+        self.body = body;
+        self.sx = 0.0;
+        self.sy = 0.0;
+        self.lines();
+        self.draw();
+    }
+
+    /// Swap in a newer version of the same document, e.g. once one of its images has loaded,
+    /// staying where we're scrolled to.
+    pub fn replace_body(&mut self, body: Body) {
+        self.body = body;
+        self.lines();
+        self.draw();
+    }
+
     pub fn update_window_scale(&mut self, width: f32, height: f32) {
         if self.width != width || self.height != height {
             self.width = width;
@@ -89,8 +110,6 @@ impl Layout {
         let mut x: f32 = 0.0;
         let mut collected_text = Vec::new();
 
-        let mut font: LayoutFont = LayoutFont::default();
-        let mut text: String = String::new();
         for token in self.body.tokens().iter() {
             let mut text_width = 0.0;
             // Don't need to process if its out of sight... Out of sight out of mind.
@@ -106,11 +125,26 @@ impl Layout {
                     self.lines.push((Vec::new(), 0.0));
                     x = 0.0;
                 }
+                super::text::TokenAction::LineBreak => {
+                    self.lines.push((collected_text.clone(), text_width));
+                    collected_text.clear();
+                    x = 0.0;
+                }
+                super::text::TokenAction::Image(image) => {
+                    // Images get a line to themselves.
+                    if !collected_text.is_empty() {
+                        self.lines.push((collected_text.clone(), text_width));
+                        collected_text.clear();
+                    }
+                    self.lines.push((vec![token.clone()], image.width as f32));
+                    x = 0.0;
+                }
                 super::text::TokenAction::Text(styled_text) => {
-                    let f = &self.get_font(&font);
-                    text_width += text_pixel_dimensions(f, &styled_text.text, self.pt).0;
-                    for word in styled_text.text.split(" ") {
-                        let word_width = text_pixel_dimensions(f, word, self.pt).0;
+                    let f = &self.get_font(&styled_text.font);
+                    let size = styled_text.font.size;
+                    text_width += text_pixel_dimensions(f, &styled_text.text, size).0;
+                    for word in styled_text.text.split_inclusive(' ') {
+                        let word_width = text_pixel_dimensions(f, word, size).0;
 
                         if word.contains('\n') {
                             self.lines.push((collected_text.clone(), text_width));
@@ -127,14 +161,11 @@ impl Layout {
                         }
 
                         x += word_width;
-                        text.push_str(word);
+                        // Every word keeps the style of the text it came from.
                         collected_text.push(TokenAction::Text(StyledText {
-                            text: text.clone(),
-                            font: font.clone(),
+                            text: word.to_string(),
+                            ..styled_text.clone()
                         }));
-
-                        font = styled_text.font.clone();
-                        text.clear();
                     }
                 }
             }
@@ -146,19 +177,24 @@ impl Layout {
 
         let mut largest_ystep = 0.0;
         for (line, _) in self.lines.clone() {
-            if self.vstep < -self.sy + 20.0 {
-                continue;
-            } else if self.vstep > self.height {
+            if self.vstep < -self.sy + 20.0 || self.vstep > self.height {
                 continue;
             }
 
             // Calculate the actual total width of this line
             let mut total_line_width = 0.0;
             for ta in &line {
-                if let super::text::TokenAction::Text(styled_text) = ta {
-                    let font = &self.get_font(&styled_text.font);
-                    let d = text_pixel_dimensions(font, &styled_text.text, self.pt);
-                    total_line_width += d.0;
+                match ta {
+                    super::text::TokenAction::Text(styled_text) => {
+                        let font = &self.get_font(&styled_text.font);
+                        let d =
+                            text_pixel_dimensions(font, &styled_text.text, styled_text.font.size);
+                        total_line_width += d.0;
+                    }
+                    super::text::TokenAction::Image(image) => {
+                        total_line_width += image.width as f32;
+                    }
+                    _ => {}
                 }
             }
 
@@ -168,11 +204,9 @@ impl Layout {
             self.hstep = -self.sx
                 + 10.0
                 + match &self.align {
-                    val if *val == "left".to_owned() => 0.0,
-                    val if *val == "right".to_owned() => available_width - total_line_width,
-                    val if *val == "center".to_owned() => {
-                        (available_width - total_line_width) / 2.0
-                    }
+                    val if *val == "left" => 0.0,
+                    val if *val == "right" => available_width - total_line_width,
+                    val if *val == "center" => (available_width - total_line_width) / 2.0,
                     _ => 0.0,
                 };
 
@@ -182,28 +216,77 @@ impl Layout {
                         self.vstep += largest_ystep * 2.;
                         // Don't reset hstep here - it's handled per line
                     }
+                    super::text::TokenAction::LineBreak => {}
+                    super::text::TokenAction::Image(image) => {
+                        self.dt.draw_image_at(
+                            self.hstep,
+                            self.vstep,
+                            &Image {
+                                width: image.width as i32,
+                                height: image.height as i32,
+                                data: &image.pixels,
+                            },
+                            &DrawOptions::new(),
+                        );
+                        self.hstep += image.width as f32;
+                        self.vstep += image.height as f32;
+                    }
                     super::text::TokenAction::Text(styled_text) => {
                         let font = &self.get_font(&styled_text.font);
-                        let d = text_pixel_dimensions(font, &styled_text.text, self.pt);
+                        let size = styled_text.font.size;
+                        let d = text_pixel_dimensions(font, &styled_text.text, size);
 
                         if largest_ystep < d.1 {
                             largest_ystep = d.1;
                         }
 
+                        let decoration = &styled_text.decoration;
+                        let baseline = self.vstep - decoration.raise;
+                        if decoration.highlight {
+                            self.dt.fill_rect(
+                                self.hstep,
+                                baseline - size * 0.8,
+                                d.0,
+                                size,
+                                &Source::Solid(SolidSource {
+                                    r: 0xff,
+                                    g: 0xf0,
+                                    b: 0x60,
+                                    a: 0xff,
+                                }),
+                                &DrawOptions::new(),
+                            );
+                        }
+
                         self.dt.draw_text(
                             font,
-                            self.pt,
+                            size,
                             &styled_text.text,
-                            Point::new(self.hstep, self.vstep), // Use calculated hstep
-                            &Source::Solid(SolidSource {
-                                r: 0,
-                                g: 0,
-                                b: 0,
-                                a: 0xff,
-                            }),
+                            Point::new(self.hstep, baseline), // Use calculated hstep
+                            &BLACK,
                             &DrawOptions::new(),
                         );
 
+                        // Lines under and through the text, a pixel or so thick depending on size.
+                        let thickness = (size / 14.0).max(1.0);
+                        let mut lines = Vec::new();
+                        if decoration.underline {
+                            lines.push(baseline + size * 0.15);
+                        }
+                        if decoration.line_through {
+                            lines.push(baseline - size * 0.3);
+                        }
+                        for y in lines {
+                            self.dt.fill_rect(
+                                self.hstep,
+                                y,
+                                d.0,
+                                thickness,
+                                &BLACK,
+                                &DrawOptions::new(),
+                            );
+                        }
+
                         self.hstep += d.0;
                     }
                 }
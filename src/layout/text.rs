@@ -1,34 +1,142 @@
 use super::LayoutFont;
-use std::rc::Rc;
+use crate::html::diagnostics::Span;
+use crate::html::dom::{Document, NodeId};
+use font_kit::family_name::FamilyName;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum NodeType {
-    Element { tag: String },
+    Element(Element),
     Text { text: String },
+    Comment { text: String },
+}
+
+/// An element's tag name and its attributes, in the order they were written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    /// Always lowercase.
+    pub tag: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+impl Element {
+    pub fn new(tag: impl Into<String>) -> Self {
+        Element {
+            tag: tag.into(),
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Valueless attributes like `disabled` have an empty value.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attribute(name).is_some()
+    }
+
+    /// Set an attribute, replacing the value of one that's already there.
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        match self
+            .attributes
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        {
+            Some((_, old)) => *old = value.to_string(),
+            None => self.attributes.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    /// Remove an attribute, handing back the value it had.
+    pub fn remove_attribute(&mut self, name: &str) -> Option<String> {
+        let position = self
+            .attributes
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(name))?;
+        Some(self.attributes.remove(position).1)
+    }
+
+    /// Add an attribute, unless it's already there. Like browsers, the first of any duplicates wins.
+    pub fn set_attribute_if_missing(&mut self, name: &str, value: &str) {
+        if !self.has_attribute(name) {
+            self.attributes.push((name.to_string(), value.to_string()));
+        }
+    }
 }
 #[derive(Debug, Clone)]
 pub struct Node {
     pub children: Vec<usize>,
     pub node_type: NodeType,
     pub parent: Option<usize>, // Index in the vec.
+    /// Where the node came from in the source. None for the ones the parser made up, like an
+    /// implied <body>, and ones added later. An element's is its start tag.
+    pub span: Option<Span>,
 }
 
 /// Hmm gotta understand linked lists in rust properly...
 
 #[derive(Debug, Clone)]
 pub struct Body {
-    text: String,
     tokens: Vec<TokenAction>,
 }
 
 impl Body {
+    /// An HTML document, parsed and then laid out from its tree.
     pub fn new(text: String) -> Self {
-        let tokens = lex(&text);
-        Self { text, tokens }
+        Body::from_document(&Document::parse(&text), &HashMap::new())
     }
 
-    pub fn text(&self) -> &str {
-        &self.text
+    /// A tree that's already been parsed, e.g. the part of a page that's downloaded so far.
+    /// `images` are the ones that have loaded so far keyed by their `src` as written, any others
+    /// show their alt text.
+    pub fn from_document(document: &Document, images: &HashMap<String, DecodedImage>) -> Self {
+        let mut flow = Flow {
+            document,
+            images,
+            tokens: Vec::new(),
+            styles: vec![Style::default()],
+            line_started: false,
+            after_space: true,
+        };
+        for root in document.roots() {
+            flow.node(root);
+        }
+        Self {
+            tokens: flow.tokens,
+        }
+    }
+
+    /// A text/plain document. Every line is kept as is, nothing gets treated as markup.
+    pub fn plain(text: String) -> Self {
+        let font = LayoutFont::default();
+        let tokens = text
+            .replace("\t", "    ")
+            .lines()
+            .flat_map(|line| {
+                [
+                    TokenAction::Text(StyledText {
+                        text: line.to_string(),
+                        font: font.clone(),
+                        decoration: Decoration::default(),
+                    }),
+                    TokenAction::LineBreak,
+                ]
+            })
+            .collect();
+        Self { tokens }
+    }
+
+    /// A standalone image document, just the decoded image on its own.
+    pub fn image(bytes: &[u8]) -> Result<Self, String> {
+        let image = DecodedImage::decode(bytes)?;
+        Ok(Self {
+            tokens: vec![TokenAction::Image(image)],
+        })
     }
 
     pub fn tokens(&self) -> Vec<TokenAction> {
@@ -36,132 +144,375 @@ impl Body {
     }
 }
 
-fn lex(text: &String) -> Vec<TokenAction> {
-    let mut buffer = String::new();
-    let mut in_tag = false;
-    let mut tag = String::new();
+/// Elements that go on lines of their own.
+const BLOCK_ELEMENTS: [&str; 41] = [
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "body",
+    "center",
+    "dd",
+    "details",
+    "dialog",
+    "div",
+    "dl",
+    "dt",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hgroup",
+    "hr",
+    "html",
+    "legend",
+    "li",
+    "listing",
+    "main",
+    "menu",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "tr",
+    "ul",
+];
 
-    let mut text = text.clone();
-    text = text.replace("&lt;", "<");
-    text = text.replace("&gt;", ">");
-    text = text.replace("&amp;", "&");
-    text = text.replace("&quot;", "\"");
-    text = text.replace("\t", "    ");
+/// Elements that are never shown, and neither is anything inside them.
+const HIDDEN_ELEMENTS: [&str; 8] = [
+    "base", "head", "link", "meta", "script", "style", "template", "title",
+];
 
-    let mut lexed: Vec<TokenAction> = Vec::new();
-    let mut font = LayoutFont::default();
+/// How an element's text looks. Each element starts from its parent's and adds its own.
+#[derive(Debug, Clone, Default)]
+struct Style {
+    font: LayoutFont,
+    decoration: Decoration,
+    /// Whitespace and line breaks are kept as written, like in a <pre>.
+    preformatted: bool,
+}
 
-    for c in text.chars() {
-        if c == '<' {
-            if !buffer.is_empty() && !in_tag {
-                // Emit text before the tag
-                lexed.push(TokenAction::Text(StyledText {
-                    text: buffer.clone(),
-                    font: font.clone(),
-                }));
-                buffer.clear();
+impl Style {
+    /// The style inside a `tag` element that's in one styled like `self`.
+    fn nested(&self, tag: &str) -> Style {
+        let mut style = self.clone();
+        let font = &mut style.font;
+        match tag {
+            "b" | "strong" => font.properties.weight = font_kit::properties::Weight::BOLD,
+            "i" | "em" => font.properties.style = font_kit::properties::Style::Italic,
+            "big" => font.size *= 1.2,
+            "small" => font.size /= 1.2,
+            "code" | "kbd" | "samp" | "tt" => font.family = FamilyName::Monospace,
+            "u" | "ins" => style.decoration.underline = true,
+            "s" | "strike" | "del" => style.decoration.line_through = true,
+            "mark" => style.decoration.highlight = true,
+            "sub" => {
+                style.decoration.raise -= font.size * 0.2;
+                font.size *= 0.8;
             }
-            in_tag = true;
-            tag.clear();
-        } else if c == '>' {
-            in_tag = false;
-            // Process the tag
-            match tag.to_lowercase().as_str() {
-                "/p" | "newline" => lexed.push(TokenAction::Newline),
-                "i" => {
-                    font = LayoutFont::default();
-                    font.properties.style = font_kit::properties::Style::Italic;
-                }
-                "/i" => {
-                    font = LayoutFont::default();
-                    font.properties.style = font_kit::properties::Style::Normal;
-                }
-                "b" => {
-                    font = LayoutFont::default();
-                    font.properties.weight = font_kit::properties::Weight::BOLD;
-                }
-                "/b" => {
-                    font = LayoutFont::default();
-                    font.properties.weight = font_kit::properties::Weight::NORMAL;
-                }
-                "big" => {
-                    font = LayoutFont::default();
-                    font.size *= 1.2;
-                }
-                "/big" => {
-                    font = LayoutFont::default();
-                    font.size = font.original_size;
-                }
-                "small" => {
-                    font = LayoutFont::default();
-                    font.size /= 1.2;
-                }
-                "/small" => {
-                    font = LayoutFont::default();
-                    font.size = font.original_size;
-                }
-                _ => {}
+            "sup" => {
+                style.decoration.raise += font.size * 0.4;
+                font.size *= 0.8;
             }
-            tag.clear();
-        } else if in_tag {
-            tag.push(c);
-        } else {
-            buffer.push(c);
+            "pre" | "listing" | "plaintext" | "textarea" => style.preformatted = true,
+            _ => {}
         }
+        style
     }
-    // Emit any remaining text
-    if !buffer.is_empty() {
-        lexed.push(TokenAction::Text(StyledText { text: buffer, font }));
-    }
-    lexed
 }
 
-pub fn show(text: &String) {
-    let mut in_tag = false;
-    let mut b = String::new();
-    for c in text.chars() {
-        if c == '<' {
-            in_tag = true;
-        } else if c == '>' {
-            in_tag = false;
-        } else if !in_tag {
-            b.push(c);
+/// Walks the DOM, turning it into the styled text and line breaks `Layout` puts on the page.
+struct Flow<'a> {
+    document: &'a Document,
+    images: &'a HashMap<String, DecodedImage>,
+    tokens: Vec<TokenAction>,
+    /// The style of every element we're in, innermost last. Never empty.
+    styles: Vec<Style>,
+    /// Whether anything's been put on the current line yet.
+    line_started: bool,
+    /// Whether the text so far ends in a space, any whitespace right after it is dropped.
+    after_space: bool,
+}
+
+impl Flow<'_> {
+    fn style(&self) -> &Style {
+        self.styles
+            .last()
+            .expect("the document's style is never popped")
+    }
+
+    /// Lay out a node and everything in it.
+    fn node(&mut self, id: NodeId) {
+        let element = match &self.document.node(id).node_type {
+            NodeType::Element(element) => element,
+            NodeType::Text { text } if self.style().preformatted => {
+                return self.preformatted_text(text);
+            }
+            NodeType::Text { text } => return self.text(text),
+            NodeType::Comment { .. } => return,
+        };
+        let tag = element.tag.as_str();
+        if HIDDEN_ELEMENTS.contains(&tag) || element.has_attribute("hidden") {
+            return;
+        }
+        match tag {
+            "br" => return self.line_break(),
+            "img" => {
+                let src = element.attribute("src").unwrap_or_default();
+                return match self.images.get(src) {
+                    Some(image) => self.image(image.clone()),
+                    // Not loaded (yet), so show what it's of instead.
+                    None => self.text(element.attribute("alt").unwrap_or_default()),
+                };
+            }
+            _ => {}
+        }
+
+        let block = BLOCK_ELEMENTS.contains(&tag);
+        if block {
+            self.end_line();
+        }
+        self.styles.push(self.style().nested(tag));
+        for child in self.document.children(id) {
+            self.node(child);
+        }
+        self.styles.pop();
+        if block {
+            self.end_line();
+        }
+        // Paragraphs get a gap after them.
+        if tag == "p" {
+            self.tokens.push(TokenAction::Newline);
         }
     }
 
-    b = b.replace("&lt;", "<");
-    b = b.replace("&gt;", ">");
-    b = b.replace("&amp;", "&");
-    b = b.replace("&quot;", "\"");
-    b = b.replace("\t", "    ");
+    /// Runs of whitespace show up as a single space, and not at all at the start of a line.
+    fn text(&mut self, text: &str) {
+        let mut collapsed = String::new();
+        for c in text.chars() {
+            if !c.is_ascii_whitespace() {
+                collapsed.push(c);
+                self.after_space = false;
+            } else if !self.after_space {
+                collapsed.push(' ');
+                self.after_space = true;
+            }
+        }
+        self.push_text(collapsed);
+    }
 
-    println!("{}", b);
-}
+    /// Text in a <pre> etc. keeps its whitespace and line breaks as written.
+    fn preformatted_text(&mut self, text: &str) {
+        for (i, line) in text.replace("\t", "    ").split('\n').enumerate() {
+            if i > 0 {
+                self.line_break();
+            }
+            self.push_text(line.to_string());
+        }
+        self.after_space = text.ends_with(|c: char| c.is_ascii_whitespace());
+    }
 
-#[derive(Debug, Clone)]
-pub enum Token {
-    Text(String),
-    Tag(String),
+    fn push_text(&mut self, text: String) {
+        if text.is_empty() {
+            return;
+        }
+        let style = self.style();
+        self.tokens.push(TokenAction::Text(StyledText {
+            text,
+            font: style.font.clone(),
+            decoration: style.decoration.clone(),
+        }));
+        self.line_started = true;
+    }
+
+    /// Layout gives images a line of their own.
+    fn image(&mut self, image: DecodedImage) {
+        self.tokens.push(TokenAction::Image(image));
+        self.line_started = false;
+        self.after_space = true;
+    }
+
+    /// Start a new line, even if that leaves an empty one like a <br> does.
+    fn line_break(&mut self) {
+        self.tokens.push(TokenAction::LineBreak);
+        self.line_started = false;
+        self.after_space = true;
+    }
+
+    /// Finish the current line if there's anything on it, so that what comes next starts afresh.
+    fn end_line(&mut self) {
+        if self.line_started {
+            self.line_break();
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum TokenAction {
     Newline,
+    /// Ends the current line without the extra gap a `Newline` leaves.
+    LineBreak,
     Text(StyledText),
+    Image(DecodedImage),
 }
 
 #[derive(Debug, Clone)]
 pub struct StyledText {
     pub text: String,
     pub font: LayoutFont,
+    pub decoration: Decoration,
+}
+
+/// What gets drawn along with the glyphs themselves.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Decoration {
+    pub underline: bool,
+    pub line_through: bool,
+    /// A highlighter pen background, like <mark> has.
+    pub highlight: bool,
+    /// How far above the baseline the text sits in pixels, negative for subscripts.
+    pub raise: f32,
 }
 
-impl Token {
-    pub fn is_text(&self) -> bool {
-        matches!(self, Token::Text(_))
+/// Decoded pixels in the premultiplied ARGB layout raqote draws from.
+#[derive(Clone)]
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Arc<Vec<u32>>,
+}
+
+impl DecodedImage {
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let rgba = image::load_from_memory(bytes)
+            .map_err(|e| format!("Failed to decode image: {}", e))?
+            .to_rgba8();
+        let pixels = rgba
+            .pixels()
+            .map(|p| {
+                let [r, g, b, a] = p.0;
+                let premultiply = |c: u8| (c as u32 * a as u32 / 255) as u8;
+                u32::from_be_bytes([a, premultiply(r), premultiply(g), premultiply(b)])
+            })
+            .collect();
+        Ok(DecodedImage {
+            width: rgba.width(),
+            height: rgba.height(),
+            pixels: Arc::new(pixels),
+        })
     }
+}
 
-    pub fn is_tag(&self) -> bool {
-        matches!(self, Token::Tag(_))
+// The pixel buffer is far too big to be useful in debug output.
+impl std::fmt::Debug for DecodedImage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecodedImage")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .finish()
     }
 }
+
+#[test]
+fn test_layout_from_tree() {
+    use font_kit::properties::{Style, Weight};
+
+    let body = Body::new(
+        "<title>T</title><p>Hello  <b>big\n<i>world</i></b></p><div hidden>x</div>a<br>\
+         <script>b</script><pre>c\n d</pre><img alt=e>"
+            .to_string(),
+    );
+    let tokens: Vec<String> = body
+        .tokens()
+        .iter()
+        .map(|token| match token {
+            TokenAction::Newline => "\\n".to_string(),
+            TokenAction::LineBreak => "|".to_string(),
+            TokenAction::Text(text) if text.font.properties.style == Style::Italic => {
+                format!("<i>{}</i>", text.text)
+            }
+            TokenAction::Text(text) if text.font.properties.weight == Weight::BOLD => {
+                format!("<b>{}</b>", text.text)
+            }
+            TokenAction::Text(text) => text.text.clone(),
+            TokenAction::Image(_) => "image".to_string(),
+        })
+        .collect();
+    assert_eq!(
+        tokens.join(""),
+        "Hello <b>big </b><i>world</i>|\\na|c| d|e|"
+    );
+
+    // Once an image has loaded it replaces its alt text.
+    let image = DecodedImage {
+        width: 1,
+        height: 1,
+        pixels: Arc::new(vec![0]),
+    };
+    let images = HashMap::from([("e.png".to_string(), image)]);
+    let body = Body::from_document(&Document::parse("a<img src=e.png alt=e>b"), &images);
+    assert!(matches!(
+        body.tokens().as_slice(),
+        [
+            TokenAction::Text(_),
+            TokenAction::Image(_),
+            TokenAction::Text(_),
+            ..
+        ]
+    ));
+}
+
+#[test]
+fn test_nested_styles() {
+    use font_kit::properties::{Style, Weight};
+
+    let body = Body::new(
+        "<b><i>a</i>b</b><big>c<i>d</i>e<small>f</small></big><u><s>g</s></u><sup>h<sub>i</sub></sup><code>j</code>"
+            .to_string(),
+    );
+    let styles: Vec<(String, LayoutFont, Decoration)> = body
+        .tokens()
+        .into_iter()
+        .filter_map(|token| match token {
+            TokenAction::Text(text) => Some((text.text, text.font, text.decoration)),
+            _ => None,
+        })
+        .collect();
+    let [a, b, c, d, e, f, g, h, i, j] = styles.as_slice() else {
+        panic!("unexpected text runs: {:?}", styles);
+    };
+    assert_eq!(
+        (a.1.properties.weight, a.1.properties.style),
+        (Weight::BOLD, Style::Italic)
+    );
+    assert_eq!(
+        (b.1.properties.weight, b.1.properties.style),
+        (Weight::BOLD, Style::Normal)
+    );
+    assert_eq!(c.1.size, 16.0 * 1.2);
+    assert_eq!(
+        (d.1.size, d.1.properties.style),
+        (16.0 * 1.2, Style::Italic)
+    );
+    assert_eq!(
+        (e.1.size, e.1.properties.style),
+        (16.0 * 1.2, Style::Normal)
+    );
+    assert_eq!(f.1.size, 16.0);
+    assert!(g.2.underline && g.2.line_through);
+    assert!(h.2.raise > 0.0 && h.1.size < 16.0);
+    assert!(i.2.raise < h.2.raise && i.1.size < h.1.size);
+    assert_eq!((j.0.as_str(), &j.1.family), ("j", &FamilyName::Monospace));
+    assert_eq!(j.2, Decoration::default());
+}
@@ -1,22 +1,166 @@
+pub mod dns;
+pub mod download;
+pub mod encoding;
+pub mod fetch;
+pub mod hsts;
 pub mod html;
 pub mod layout;
+pub mod mime;
+pub mod netlog;
+pub mod referrer;
 pub mod renderer;
 pub mod url;
+pub mod websocket;
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!(
-            "Error no site given!!!\nUsage: {} <url1> <url2> ...",
-            args[0]
-        );
-        std::process::exit(1);
-    }
-    for arg in args.iter().skip(1) {
-        if let Err(e) = url::load(arg) {
+    let mut urls = Vec::new();
+    let mut downloads = Vec::new();
+    let mut har = None;
+    let mut websockets = Vec::new();
+    let mut messages = Vec::new();
+    let mut dump_dom = false;
+    let mut parse_errors = false;
+    let mut options = args.iter().skip(1);
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--max-body-size" => {
+                let bytes = options
+                    .next()
+                    .and_then(|value| value.parse::<usize>().ok())
+                    .unwrap_or_else(|| usage(&args[0], "--max-body-size needs a number of bytes"));
+                url::set_max_body_size(bytes);
+            }
+            "--resolve" => {
+                let spec = options
+                    .next()
+                    .unwrap_or_else(|| usage(&args[0], "--resolve needs host:port:address"));
+                if let Err(e) = dns::resolver().add_override_spec(spec) {
+                    usage(&args[0], &e);
+                }
+            }
+            "--download" => {
+                let url = options
+                    .next()
+                    .unwrap_or_else(|| usage(&args[0], "--download needs a url"));
+                downloads.push(url);
+            }
+            "--har" => {
+                let path = options
+                    .next()
+                    .unwrap_or_else(|| usage(&args[0], "--har needs a file to write to"));
+                netlog::enable();
+                har = Some(path);
+            }
+            "--websocket" => {
+                let url = options
+                    .next()
+                    .unwrap_or_else(|| usage(&args[0], "--websocket needs a ws:// or wss:// url"));
+                websockets.push(url);
+            }
+            "--send" => {
+                let text = options
+                    .next()
+                    .unwrap_or_else(|| usage(&args[0], "--send needs a message"));
+                messages.push(text.as_str());
+            }
+            "--dump-dom" => dump_dom = true,
+            "--parse-errors" => parse_errors = true,
+            _ => urls.push(arg),
+        }
+    }
+    if urls.is_empty() && downloads.is_empty() && websockets.is_empty() {
+        usage(&args[0], "Error no site given!!!");
+    }
+    for arg in downloads {
+        if let Err(e) = download(arg) {
+            eprintln!("Error downloading {}: {}", arg, e);
+        }
+    }
+    for arg in websockets {
+        if let Err(e) = watch_websocket(arg, &messages) {
+            eprintln!("Error on WebSocket {}: {}", arg, e);
+        }
+    }
+    for arg in urls {
+        let result = if dump_dom || parse_errors {
+            inspect(arg, dump_dom, parse_errors)
+        } else {
+            url::load(arg)
+        };
+        if let Err(e) = result {
             eprintln!("Error loading URL {}: {}", arg, e);
         }
     }
+    if let Some(Err(e)) = har.map(|path| netlog::export_har(std::path::Path::new(path))) {
+        eprintln!("{}", e);
+    }
+}
+
+/// Print the page as HTML once it's been parsed and/or list its HTML parse errors, each with the
+/// bit of source it's about, instead of opening a window for it.
+fn inspect(url: &str, dump_dom: bool, parse_errors: bool) -> Result<(), String> {
+    let (_, response) = url::fetch(url::URL::from_string(url)?, |_, _| {})?;
+    let source = response.text();
+    let mut parser = html::HTMLParser::new(source.clone());
+    parser.finish();
+    if dump_dom {
+        println!("{}", parser.with_document(|document| document.to_html()));
+    }
+    if parse_errors {
+        for error in &parser.errors {
+            println!("{}\n", error.render(&source));
+        }
+        eprintln!("{} parse errors", parser.errors.len());
+    }
+    Ok(())
+}
+
+/// Send the messages, then print everything the WebSocket sends until the server closes it.
+fn watch_websocket(url: &str, messages: &[&str]) -> Result<(), String> {
+    let url = url::URL::from_string(url)?;
+    let mut socket = websocket::WebSocket::connect(&url)?;
+    for message in messages {
+        socket.send_text(message)?;
+    }
+    loop {
+        match socket.read_message()? {
+            websocket::Message::Text(text) => println!("{}", text),
+            websocket::Message::Binary(data) => println!("<{} bytes of binary data>", data.len()),
+            websocket::Message::Pong(_) => {}
+            websocket::Message::Close(reason) => {
+                if let Some((code, reason)) = reason {
+                    eprintln!("Closed: {} {}", code, reason);
+                }
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Save a URL into the current directory, showing progress as it goes.
+fn download(url: &str) -> Result<(), String> {
+    let url = url::URL::from_string(url)?;
+    let manager = download::DownloadManager::new(".");
+    let path = manager.download(url, |progress| match progress.total {
+        Some(total) if total > 0 => eprint!(
+            "\rDownloading: {}% ({} of {} KiB)",
+            progress.received * 100 / total,
+            progress.received / 1024,
+            total / 1024
+        ),
+        _ => eprint!("\rDownloading: {} KiB", progress.received / 1024),
+    })?;
+    eprintln!("\nSaved {}", path.display());
+    Ok(())
+}
+
+fn usage(program: &str, error: &str) -> ! {
+    eprintln!(
+        "{}\nUsage: {} [--max-body-size <bytes>] [--resolve <host:port:address>] [--download <url>] [--har <file>] [--websocket <url>] [--send <text>] [--dump-dom] [--parse-errors] <url1> <url2> ...",
+        error, program
+    );
+    std::process::exit(1);
 }
 
 /*
@@ -0,0 +1,274 @@
+/*
+    Media types and content sniffing.
+    Parsing follows https://mimesniff.spec.whatwg.org/#parsing-a-mime-type loosely, and sniffing is a
+    cut down version of "identifying a resource with an unknown MIME type" from the same spec.
+
+    The idea is that the response decides what sort of document we show:
+    - HTML goes to the parser.
+    - Plain text (and other text-ish types like CSS or JSON) is shown preformatted.
+    - Images we can decode get their own image document.
+    - Anything else is offered as a download.
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaType {
+    pub kind: String,
+    pub subtype: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Html,
+    PlainText,
+    Image,
+    Download,
+}
+
+impl MediaType {
+    pub fn new(kind: &str, subtype: &str) -> Self {
+        MediaType {
+            kind: kind.to_string(),
+            subtype: subtype.to_string(),
+            parameters: Vec::new(),
+        }
+    }
+
+    /// Parse a header value like `text/html; charset=utf-8`. Type, subtype and parameter names
+    /// are lowercased, parameter values are kept as they are (minus quotes).
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.split(';');
+        let (kind, subtype) = parts.next()?.trim().split_once('/')?;
+        let (kind, subtype) = (kind.trim(), subtype.trim());
+        let is_token = |s: &str| {
+            !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+        };
+        if !is_token(kind) || !is_token(subtype) {
+            return None;
+        }
+
+        let parameters = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                let name = name.trim().to_ascii_lowercase();
+                let value = value.trim().trim_matches('"').to_string();
+                (!name.is_empty()).then_some((name, value))
+            })
+            .collect();
+
+        Some(MediaType {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            parameters,
+        })
+    }
+
+    /// The type and subtype without any parameters, e.g. `text/html`.
+    pub fn essence(&self) -> String {
+        format!("{}/{}", self.kind, self.subtype)
+    }
+
+    pub fn parameter(&self, name: &str) -> Option<&str> {
+        self.parameters
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Which sort of document a response with this type should become.
+    pub fn document_kind(&self) -> DocumentKind {
+        match (self.kind.as_str(), self.subtype.as_str()) {
+            ("text", "html") | ("application", "xhtml+xml") => DocumentKind::Html,
+            ("text", _)
+            | ("application", "json")
+            | ("application", "javascript")
+            | ("application", "xml")
+            | ("application", "ecmascript") => DocumentKind::PlainText,
+            ("image", "png" | "jpeg" | "jpg" | "pjpeg" | "gif" | "bmp" | "webp")
+            | ("image", "x-icon" | "vnd.microsoft.icon") => DocumentKind::Image,
+            _ => DocumentKind::Download,
+        }
+    }
+}
+
+impl std::fmt::Display for MediaType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.essence())?;
+        for (name, value) in &self.parameters {
+            write!(f, ";{}={}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Work out the media type of a response. If the server told us, we believe it, otherwise we sniff
+/// the bytes. `nosniff` is set when the server sent `X-Content-Type-Options: nosniff`, in which
+/// case a missing or broken header means we treat it as an opaque download.
+pub fn resolve(content_type: Option<&str>, nosniff: bool, bytes: &[u8]) -> MediaType {
+    match content_type.and_then(MediaType::parse) {
+        Some(media_type) => media_type,
+        None if nosniff => MediaType::new("application", "octet-stream"),
+        None => sniff(bytes),
+    }
+}
+
+/// The media type that goes with a file name's extension, for local files which don't come with a
+/// Content-Type. None if we don't know the extension, in which case sniff it.
+pub fn from_extension(path: &str) -> Option<MediaType> {
+    let name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+    let (_, extension) = name.rsplit_once('.')?;
+    let (kind, subtype) = match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => ("text", "html"),
+        "xhtml" | "xht" => ("application", "xhtml+xml"),
+        "txt" | "text" => ("text", "plain"),
+        "css" => ("text", "css"),
+        "js" | "mjs" => ("text", "javascript"),
+        "json" => ("application", "json"),
+        "xml" => ("text", "xml"),
+        "png" => ("image", "png"),
+        "jpg" | "jpeg" => ("image", "jpeg"),
+        "gif" => ("image", "gif"),
+        "bmp" => ("image", "bmp"),
+        "webp" => ("image", "webp"),
+        "ico" => ("image", "x-icon"),
+        "svg" => ("image", "svg+xml"),
+        "pdf" => ("application", "pdf"),
+        "zip" => ("application", "zip"),
+        "gz" => ("application", "gzip"),
+        _ => return None,
+    };
+    Some(MediaType::new(kind, subtype))
+}
+
+/// Guess the media type of some bytes with no (usable) Content-Type.
+pub fn sniff(bytes: &[u8]) -> MediaType {
+    // Byte order marks mean text.
+    if bytes.starts_with(&[0xEF, 0xBB, 0xBF])
+        || bytes.starts_with(&[0xFE, 0xFF])
+        || bytes.starts_with(&[0xFF, 0xFE])
+    {
+        return MediaType::new("text", "plain");
+    }
+
+    if let Some(media_type) = sniff_markup(bytes) {
+        return media_type;
+    }
+
+    const SIGNATURES: &[(&[u8], &str, &str)] = &[
+        (b"%PDF-", "application", "pdf"),
+        (b"\x89PNG\r\n\x1A\n", "image", "png"),
+        (b"\xFF\xD8\xFF", "image", "jpeg"),
+        (b"GIF87a", "image", "gif"),
+        (b"GIF89a", "image", "gif"),
+        (b"BM", "image", "bmp"),
+        (b"\x00\x00\x01\x00", "image", "x-icon"),
+        (b"\x1F\x8B\x08", "application", "x-gzip"),
+        (b"PK\x03\x04", "application", "zip"),
+    ];
+    for (signature, kind, subtype) in SIGNATURES {
+        if bytes.starts_with(signature) {
+            return MediaType::new(kind, subtype);
+        }
+    }
+    // WebP is "RIFF" + 4 length bytes + "WEBPVP".
+    if bytes.len() >= 14 && bytes.starts_with(b"RIFF") && &bytes[8..14] == b"WEBPVP" {
+        return MediaType::new("image", "webp");
+    }
+
+    let is_binary = bytes
+        .iter()
+        .take(1445)
+        .any(|&b| matches!(b, 0x00..=0x08 | 0x0B | 0x0E..=0x1A | 0x1C..=0x1F));
+    if is_binary {
+        MediaType::new("application", "octet-stream")
+    } else {
+        MediaType::new("text", "plain")
+    }
+}
+
+/// The HTML and XML patterns from the spec. Leading whitespace is skipped and the tag name has to
+/// be followed by a space or `>` so `<bold>` isn't mistaken for `<b>`.
+fn sniff_markup(bytes: &[u8]) -> Option<MediaType> {
+    const HTML_PATTERNS: &[&[u8]] = &[
+        b"<!DOCTYPE HTML",
+        b"<HTML",
+        b"<HEAD",
+        b"<SCRIPT",
+        b"<IFRAME",
+        b"<H1",
+        b"<DIV",
+        b"<FONT",
+        b"<TABLE",
+        b"<A",
+        b"<STYLE",
+        b"<TITLE",
+        b"<B",
+        b"<BODY",
+        b"<BR",
+        b"<P",
+        b"<!--",
+    ];
+
+    let start = bytes
+        .iter()
+        .position(|b| !matches!(b, b'\t' | b'\n' | b'\x0C' | b'\r' | b' '))?;
+    let bytes = &bytes[start..];
+
+    for pattern in HTML_PATTERNS {
+        if bytes.len() > pattern.len() && bytes[..pattern.len()].eq_ignore_ascii_case(pattern) {
+            let terminator = bytes[pattern.len()];
+            if terminator == b' ' || terminator == b'>' {
+                return Some(MediaType::new("text", "html"));
+            }
+        }
+    }
+    if bytes.starts_with(b"<?xml") {
+        return Some(MediaType::new("text", "xml"));
+    }
+    None
+}
+
+#[test]
+fn test_media_type_dispatch() {
+    let html = MediaType::parse("Text/HTML; Charset=\"UTF-8\"").unwrap();
+    assert_eq!(html.essence(), "text/html");
+    assert_eq!(html.parameter("charset"), Some("UTF-8"));
+    assert_eq!(html.document_kind(), DocumentKind::Html);
+    assert_eq!(
+        MediaType::parse("application/zip").unwrap().document_kind(),
+        DocumentKind::Download
+    );
+    assert_eq!(MediaType::parse("nonsense"), None);
+
+    // Sniffing only kicks in without a usable header.
+    assert_eq!(resolve(None, false, b"  <p>hi").essence(), "text/html");
+    assert_eq!(resolve(None, false, b"<bold>").essence(), "text/plain");
+    assert_eq!(
+        resolve(None, false, b"\x89PNG\r\n\x1A\n").essence(),
+        "image/png"
+    );
+    assert_eq!(
+        resolve(None, false, b"\x00\x01").essence(),
+        "application/octet-stream"
+    );
+    assert_eq!(
+        resolve(None, true, b"<html>").essence(),
+        "application/octet-stream"
+    );
+    assert_eq!(
+        resolve(Some("text/plain"), false, b"<html>").essence(),
+        "text/plain"
+    );
+
+    // Local files go by their extension, whatever they start with.
+    let extension = |path| from_extension(path).map(|media_type| media_type.essence());
+    assert_eq!(extension("/tmp/page.HTM").as_deref(), Some("text/html"));
+    assert_eq!(
+        extension("C:\\pics\\cat.jpeg").as_deref(),
+        Some("image/jpeg")
+    );
+    assert_eq!(extension("/tmp/v1.2/README"), None);
+    assert_eq!(extension("/tmp/archive.unknown"), None);
+}
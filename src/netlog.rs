@@ -0,0 +1,359 @@
+/*
+    A log of network activity that can be saved as a HAR file, http://www.softwareishard.com/blog/har-12-spec/.
+    HAR files open in the network panel of most browser devtools, which beats reading eprintln!s.
+
+    Logging is off until `enable` is called, so normal browsing doesn't keep every request around.
+    A request's entry starts when it's sent and gets filled in phase by phase (setup, DNS, connect, TLS,
+    send, wait for the first byte, receive), then lands in the log once its body has been read.
+*/
+
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Start recording requests.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The parts of a request's timeline HAR knows about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Anything before the request gets going, like setting up TLS.
+    Blocked,
+    Dns,
+    Connect,
+    Tls,
+    Send,
+    /// Waiting for the first byte of the response.
+    Wait,
+    Receive,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    pub blocked: Option<Duration>,
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    pub send: Option<Duration>,
+    pub wait: Option<Duration>,
+    pub receive: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub started: SystemTime,
+    pub method: String,
+    pub url: String,
+    pub request_headers: Vec<(String, String)>,
+    /// 0 if we never got a response.
+    pub status: u16,
+    pub status_text: String,
+    pub http_version: String,
+    pub response_headers: Vec<(String, String)>,
+    pub body_size: usize,
+    pub server_ip: Option<IpAddr>,
+    pub timings: Timings,
+    pub total: Duration,
+    pub error: Option<String>,
+}
+
+/// An entry for a request that's still going.
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    entry: Entry,
+    start: Instant,
+    last: Instant,
+}
+
+impl PendingEntry {
+    /// Start timing a request, or None if logging is off.
+    pub fn start(method: &str, url: &str) -> Option<Self> {
+        is_enabled().then(|| PendingEntry::new(method, url))
+    }
+
+    /// Start timing a request whether logging is on or not.
+    pub fn new(method: &str, url: &str) -> Self {
+        let now = Instant::now();
+        PendingEntry {
+            entry: Entry {
+                started: SystemTime::now(),
+                method: method.to_string(),
+                url: url.to_string(),
+                request_headers: Vec::new(),
+                status: 0,
+                status_text: String::new(),
+                http_version: "HTTP/1.1".to_string(),
+                response_headers: Vec::new(),
+                body_size: 0,
+                server_ip: None,
+                timings: Timings::default(),
+                total: Duration::ZERO,
+                error: None,
+            },
+            start: now,
+            last: now,
+        }
+    }
+
+    /// Mark the end of a phase, it gets the time since the previous phase ended.
+    pub fn phase(&mut self, phase: Phase) {
+        let now = Instant::now();
+        let elapsed = Some(now - self.last);
+        self.last = now;
+        let timings = &mut self.entry.timings;
+        match phase {
+            Phase::Blocked => timings.blocked = elapsed,
+            Phase::Dns => timings.dns = elapsed,
+            Phase::Connect => timings.connect = elapsed,
+            Phase::Tls => timings.tls = elapsed,
+            Phase::Send => timings.send = elapsed,
+            Phase::Wait => timings.wait = elapsed,
+            Phase::Receive => timings.receive = elapsed,
+        }
+    }
+
+    pub fn set_server_ip(&mut self, ip: IpAddr) {
+        self.entry.server_ip = Some(ip);
+    }
+
+    /// Record the request head we sent, as raw text.
+    pub fn set_request(&mut self, request: &str) {
+        self.entry.request_headers = request
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+    }
+
+    pub fn set_response<'a>(
+        &mut self,
+        status: &str,
+        headers: impl Iterator<Item = (&'a String, &'a String)>,
+    ) {
+        let mut parts = status.splitn(3, ' ');
+        self.entry.http_version = parts.next().unwrap_or_default().to_string();
+        self.entry.status = parts
+            .next()
+            .and_then(|code| code.parse().ok())
+            .unwrap_or_default();
+        self.entry.status_text = parts.next().unwrap_or_default().to_string();
+        self.entry.response_headers = headers.map(|(k, v)| (k.clone(), v.clone())).collect();
+    }
+
+    /// Finish the entry and add it to the log.
+    pub fn finish(mut self, body_size: usize, error: Option<String>) {
+        self.entry.body_size = body_size;
+        self.entry.error = error;
+        self.entry.total = self.start.elapsed();
+        if let Ok(mut entries) = ENTRIES.lock() {
+            entries.push(self.entry);
+        }
+    }
+}
+
+/// Everything recorded so far.
+pub fn entries() -> Vec<Entry> {
+    ENTRIES.lock().map(|e| e.clone()).unwrap_or_default()
+}
+
+/// Write the log out as a HAR file.
+pub fn export_har(path: &Path) -> Result<(), String> {
+    std::fs::write(path, to_har(&entries()))
+        .map_err(|e| format!("Failed to write HAR file {}: {}", path.display(), e))
+}
+
+pub fn to_har(entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries.iter().map(entry_json).collect();
+    format!(
+        "{{\"log\":{{\"version\":\"1.2\",\"creator\":{{\"name\":\"{}\",\"version\":\"{}\"}},\"pages\":[],\"entries\":[{}]}}}}\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        entries.join(",")
+    )
+}
+
+fn entry_json(entry: &Entry) -> String {
+    let ms = |d: Option<Duration>| d.map_or(-1.0, |d| d.as_secs_f64() * 1000.0);
+    let t = &entry.timings;
+    // HAR counts TLS as part of connecting.
+    let connect = match (t.connect, t.tls) {
+        (Some(connect), Some(tls)) => Some(connect + tls),
+        (connect, _) => connect,
+    };
+    let mime_type = entry
+        .response_headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Content-Type"))
+        .map_or("", |(_, value)| value.as_str());
+
+    let mut json = format!(
+        "{{\"startedDateTime\":{},\"time\":{:.3},\"request\":{{\"method\":{},\"url\":{},\"httpVersion\":\"HTTP/1.1\",\"cookies\":[],\"headers\":{},\"queryString\":[],\"headersSize\":-1,\"bodySize\":0}},",
+        json_string(&iso8601(entry.started)),
+        entry.total.as_secs_f64() * 1000.0,
+        json_string(&entry.method),
+        json_string(&entry.url),
+        headers_json(&entry.request_headers),
+    );
+    json.push_str(&format!(
+        "\"response\":{{\"status\":{},\"statusText\":{},\"httpVersion\":{},\"cookies\":[],\"headers\":{},\"content\":{{\"size\":{},\"mimeType\":{}}},\"redirectURL\":{},\"headersSize\":-1,\"bodySize\":{}}},",
+        entry.status,
+        json_string(&entry.status_text),
+        json_string(&entry.http_version),
+        headers_json(&entry.response_headers),
+        entry.body_size,
+        json_string(mime_type),
+        json_string(
+            entry
+                .response_headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Location"))
+                .map_or("", |(_, value)| value.as_str())
+        ),
+        entry.body_size,
+    ));
+    json.push_str(&format!(
+        "\"cache\":{{}},\"timings\":{{\"blocked\":{:.3},\"dns\":{:.3},\"connect\":{:.3},\"ssl\":{:.3},\"send\":{:.3},\"wait\":{:.3},\"receive\":{:.3}}}",
+        ms(t.blocked),
+        ms(t.dns),
+        ms(connect),
+        ms(t.tls),
+        ms(t.send).max(0.0),
+        ms(t.wait).max(0.0),
+        ms(t.receive).max(0.0),
+    ));
+    if let Some(ip) = entry.server_ip {
+        json.push_str(&format!(
+            ",\"serverIPAddress\":{}",
+            json_string(&ip.to_string())
+        ));
+    }
+    if let Some(error) = &entry.error {
+        json.push_str(&format!(",\"_error\":{}", json_string(error)));
+    }
+    json.push('}');
+    json
+}
+
+fn headers_json(headers: &[(String, String)]) -> String {
+    let headers: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{{\"name\":{},\"value\":{}}}",
+                json_string(name),
+                json_string(value)
+            )
+        })
+        .collect();
+    format!("[{}]", headers.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format a time like `2025-05-26T15:07:50.123Z`.
+fn iso8601(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, from Howard Hinnant's date algorithms.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[test]
+fn test_har_output() {
+    assert_eq!(
+        iso8601(UNIX_EPOCH + Duration::from_millis(1748272070123)),
+        "2025-05-26T15:07:50.123Z"
+    );
+    assert_eq!(json_string("a\"b\\\n\u{1}"), "\"a\\\"b\\\\\\n\\u0001\"");
+
+    let entry = Entry {
+        started: UNIX_EPOCH,
+        method: "GET".to_string(),
+        url: "http://example.com/".to_string(),
+        request_headers: vec![("Host".to_string(), "example.com".to_string())],
+        status: 200,
+        status_text: "OK".to_string(),
+        http_version: "HTTP/1.1".to_string(),
+        response_headers: vec![("Content-Type".to_string(), "text/html".to_string())],
+        body_size: 42,
+        server_ip: Some("93.184.216.34".parse().unwrap()),
+        timings: Timings {
+            dns: Some(Duration::from_millis(1)),
+            connect: Some(Duration::from_millis(2)),
+            ..Timings::default()
+        },
+        total: Duration::from_millis(10),
+        error: None,
+    };
+    let har: serde_json::Value = serde_json::from_str(&to_har(&[entry])).unwrap();
+    assert_eq!(har["log"]["version"], "1.2");
+    let entry = &har["log"]["entries"][0];
+    assert_eq!(entry["startedDateTime"], "1970-01-01T00:00:00.000Z");
+    assert_eq!(entry["time"], 10.0);
+    assert_eq!(entry["request"]["method"], "GET");
+    assert_eq!(entry["request"]["headers"][0]["name"], "Host");
+    assert_eq!(entry["response"]["status"], 200);
+    assert_eq!(entry["response"]["content"]["size"], 42);
+    assert_eq!(entry["response"]["content"]["mimeType"], "text/html");
+    let timings = &entry["timings"];
+    assert_eq!(timings["blocked"], -1.0);
+    assert_eq!(timings["dns"], 1.0);
+    assert_eq!(timings["connect"], 2.0);
+    assert_eq!(timings["ssl"], -1.0);
+    assert_eq!(timings["wait"], 0.0);
+    assert_eq!(entry["serverIPAddress"], "93.184.216.34");
+    assert!(entry.get("_error").is_none());
+}
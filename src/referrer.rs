@@ -0,0 +1,186 @@
+/*
+    Referrer Policy, https://w3c.github.io/webappsec-referrer-policy/.
+    Decides what goes in the `Referer` header (and the `Origin` header for requests that aren't
+    GETs) when a page loads something: the full URL, just the origin, or nothing at all.
+
+    A document's policy comes from its `Referrer-Policy` header and can then be changed by
+    `<meta name="referrer">`. A redirect response can change the policy for the rest of the chain.
+    The default is strict-origin-when-cross-origin, same as every current browser, which means the
+    full URL is only ever sent to the same origin and nothing at all on an HTTPS→HTTP downgrade.
+*/
+
+use crate::{html::dom::Document, url::URL};
+
+/// Referrers longer than this get cut down to their origin.
+const MAX_REFERRER_LENGTH: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReferrerPolicy {
+    NoReferrer,
+    NoReferrerWhenDowngrade,
+    SameOrigin,
+    Origin,
+    StrictOrigin,
+    OriginWhenCrossOrigin,
+    #[default]
+    StrictOriginWhenCrossOrigin,
+    UnsafeUrl,
+}
+
+impl ReferrerPolicy {
+    pub fn from_token(token: &str) -> Option<Self> {
+        match token.trim().to_ascii_lowercase().as_str() {
+            "no-referrer" => Some(ReferrerPolicy::NoReferrer),
+            "no-referrer-when-downgrade" => Some(ReferrerPolicy::NoReferrerWhenDowngrade),
+            "same-origin" => Some(ReferrerPolicy::SameOrigin),
+            "origin" => Some(ReferrerPolicy::Origin),
+            "strict-origin" => Some(ReferrerPolicy::StrictOrigin),
+            "origin-when-cross-origin" => Some(ReferrerPolicy::OriginWhenCrossOrigin),
+            "strict-origin-when-cross-origin" => Some(ReferrerPolicy::StrictOriginWhenCrossOrigin),
+            "unsafe-url" => Some(ReferrerPolicy::UnsafeUrl),
+            _ => None,
+        }
+    }
+
+    /// Parse a `Referrer-Policy` header. It can list several policies so that newer ones can fall
+    /// back to older ones, the last one we understand wins.
+    pub fn from_header(value: &str) -> Option<Self> {
+        value.split(',').filter_map(Self::from_token).next_back()
+    }
+
+    /// The content of the last `<meta name="referrer">` in a document. The legacy keywords from
+    /// before the spec are still accepted here.
+    pub fn from_meta(document: &Document) -> Option<Self> {
+        document
+            .get_elements_by_tag_name("meta")
+            .into_iter()
+            .filter_map(|id| document.element(id))
+            .filter(|meta| {
+                meta.attribute("name")
+                    .is_some_and(|name| name.eq_ignore_ascii_case("referrer"))
+            })
+            .filter_map(|meta| {
+                let content = meta.attribute("content").unwrap_or_default();
+                match content.trim().to_ascii_lowercase().as_str() {
+                    "never" => Some(ReferrerPolicy::NoReferrer),
+                    "default" => Some(ReferrerPolicy::StrictOriginWhenCrossOrigin),
+                    "always" => Some(ReferrerPolicy::UnsafeUrl),
+                    "origin-when-crossorigin" => Some(ReferrerPolicy::OriginWhenCrossOrigin),
+                    token => Self::from_token(token),
+                }
+            })
+            .next_back()
+    }
+}
+
+/// What to send as `Referer` when `referrer` requests `target`, or None for nothing.
+pub fn referrer_value(policy: ReferrerPolicy, referrer: &URL, target: &URL) -> Option<String> {
+    // Only http(s) documents have a URL worth sending.
+    let origin = referrer.origin()?;
+    let origin_only = format!("{}/", origin);
+    let mut full = referrer.build();
+    if full.len() > MAX_REFERRER_LENGTH {
+        full = origin_only.clone();
+    }
+    let same_origin = target.origin().as_deref() == Some(origin.as_str());
+    let downgrade = referrer.is_secure() && !target.is_potentially_trustworthy();
+
+    match policy {
+        ReferrerPolicy::NoReferrer => None,
+        ReferrerPolicy::Origin => Some(origin_only),
+        ReferrerPolicy::UnsafeUrl => Some(full),
+        ReferrerPolicy::StrictOrigin => (!downgrade).then_some(origin_only),
+        ReferrerPolicy::StrictOriginWhenCrossOrigin if same_origin => Some(full),
+        ReferrerPolicy::StrictOriginWhenCrossOrigin => (!downgrade).then_some(origin_only),
+        ReferrerPolicy::SameOrigin => same_origin.then_some(full),
+        ReferrerPolicy::OriginWhenCrossOrigin if same_origin => Some(full),
+        ReferrerPolicy::OriginWhenCrossOrigin => Some(origin_only),
+        ReferrerPolicy::NoReferrerWhenDowngrade => (!downgrade).then_some(full),
+    }
+}
+
+/// What to send as `Origin` when `initiator` requests `target`. Unlike the referrer this is always
+/// sent, policies that would hide it send `null` instead.
+pub fn origin_value(policy: ReferrerPolicy, initiator: &URL, target: &URL) -> String {
+    let Some(origin) = initiator.origin() else {
+        return "null".to_string();
+    };
+    let same_origin = target.origin().as_deref() == Some(origin.as_str());
+    let downgrade = initiator.is_secure() && !target.is_potentially_trustworthy();
+    let hidden = match policy {
+        ReferrerPolicy::NoReferrer => true,
+        ReferrerPolicy::NoReferrerWhenDowngrade
+        | ReferrerPolicy::StrictOrigin
+        | ReferrerPolicy::StrictOriginWhenCrossOrigin => downgrade,
+        ReferrerPolicy::SameOrigin => !same_origin,
+        ReferrerPolicy::Origin
+        | ReferrerPolicy::OriginWhenCrossOrigin
+        | ReferrerPolicy::UnsafeUrl => false,
+    };
+    if hidden { "null".to_string() } else { origin }
+}
+
+#[test]
+fn test_referrer_policy() {
+    assert_eq!(
+        ReferrerPolicy::from_header("no-referrer, bogus, same-origin, newer-policy"),
+        Some(ReferrerPolicy::SameOrigin)
+    );
+    assert_eq!(ReferrerPolicy::from_header("bogus"), None);
+    assert_eq!(
+        ReferrerPolicy::from_meta(&Document::parse(
+            "<meta name=referrer content=never><META NAME=\"Referrer\" content=\"origin\">"
+        )),
+        Some(ReferrerPolicy::Origin)
+    );
+    // Only real <meta> elements count, not ones in scripts, comments or text.
+    let hidden = "<script>'<meta name=referrer content=never>'</script>\
+        <!-- <meta name=referrer content=never> --><textarea><meta name=referrer content=never>\
+        </textarea><metadata name=referrer content=never>";
+    assert_eq!(ReferrerPolicy::from_meta(&Document::parse(hidden)), None);
+
+    let page = URL::from_string("https://example.com/private/page?id=1").unwrap();
+    let same = URL::from_string("https://example.com/image.png").unwrap();
+    let cross = URL::from_string("https://other.org/").unwrap();
+    let downgrade = URL::from_string("http://other.org/").unwrap();
+    let local = URL::from_string("http://localhost:8000/").unwrap();
+
+    let policy = ReferrerPolicy::default();
+    assert_eq!(
+        referrer_value(policy, &page, &same).as_deref(),
+        Some("https://example.com/private/page?id=1")
+    );
+    assert_eq!(
+        referrer_value(policy, &page, &cross).as_deref(),
+        Some("https://example.com/")
+    );
+    assert_eq!(referrer_value(policy, &page, &downgrade), None);
+    assert!(referrer_value(policy, &page, &local).is_some());
+    assert_eq!(
+        referrer_value(ReferrerPolicy::NoReferrer, &page, &same),
+        None
+    );
+    assert_eq!(
+        referrer_value(ReferrerPolicy::UnsafeUrl, &page, &downgrade).as_deref(),
+        Some("https://example.com/private/page?id=1")
+    );
+
+    assert_eq!(origin_value(policy, &page, &cross), "https://example.com");
+    assert_eq!(origin_value(policy, &page, &downgrade), "null");
+    assert_eq!(
+        origin_value(ReferrerPolicy::SameOrigin, &page, &cross),
+        "null"
+    );
+
+    // Redirects carry the referrer along, and the redirect can change the policy.
+    let mut url = same.clone();
+    url.set_referrer(&page, ReferrerPolicy::default());
+    let redirected = url.redirect_to("http://other.org/").unwrap();
+    assert!(!redirected.request_text().contains("Referer"));
+    let redirected = url.redirect_to("/elsewhere").unwrap();
+    assert!(
+        redirected
+            .request_text()
+            .contains("Referer: https://example.com/private/page?id=1\r\n")
+    );
+}
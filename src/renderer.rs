@@ -2,27 +2,32 @@
 #![forbid(unsafe_code)]
 
 use pixels::{Error, Pixels, SurfaceTexture};
+use std::collections::HashMap;
 use std::time::Instant;
 use winit::dpi::LogicalSize;
 use winit::event::{Event, WindowEvent};
-use winit::event_loop::EventLoop;
+use winit::event_loop::EventLoopBuilder;
 use winit::keyboard::KeyCode;
 use winit::window::WindowBuilder;
 use winit_input_helper::WinitInputHelper;
 
-use crate::layout::text::Body;
+use crate::fetch::{Content, DEFAULT_WORKERS, Destination, FetchEvent, FetchService, Fetched};
+use crate::layout::text::{Body, DecodedImage};
+use crate::url::URL;
 
 const WIDTH: u32 = 500;
 const HEIGHT: u32 = 500;
 
 // Should parse renderer arguments here. Like show all
-pub fn init_renderer(body: Body) -> Result<(), Error> {
-    let event_loop = EventLoop::new().unwrap();
+pub fn init_renderer(url: URL) -> Result<(), Error> {
+    let event_loop = EventLoopBuilder::<FetchEvent>::with_user_event()
+        .build()
+        .unwrap();
     let mut input = WinitInputHelper::new();
     let window = {
         let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
         WindowBuilder::new()
-            .with_title("Hello Raqote")
+            .with_title(format!("Loading {}", url.build()))
             .with_inner_size(size)
             .with_min_inner_size(size)
             .build(&event_loop)
@@ -34,14 +39,136 @@ pub fn init_renderer(body: Body) -> Result<(), Error> {
         let surface_texture = SurfaceTexture::new(window_size.width, window_size.height, &window);
 
         let pixels = Pixels::new(WIDTH, HEIGHT, surface_texture)?;
-        let layout = crate::layout::renderer::Layout::new(WIDTH as f32, HEIGHT as f32, body);
+        let loading = Body::plain(format!("Loading {}...", url.build()));
+        let mut layout = crate::layout::renderer::Layout::new(WIDTH as f32, HEIGHT as f32, loading);
+        layout.draw();
 
         (pixels, layout)
     };
 
+    // Fetches finish on the worker threads, the proxy hands their events back to this loop.
+    let proxy = std::sync::Mutex::new(event_loop.create_proxy());
+    let fetcher = FetchService::new(DEFAULT_WORKERS, move |event| {
+        if let Ok(proxy) = proxy.lock() {
+            let _ = proxy.send_event(event);
+        }
+    });
+    let mut pending = 1;
+    let mut loading_url = url.build();
+    let document_id = fetcher.fetch(url, Destination::Document);
+    // The page's stylesheets and images, by fetch, with their href or src as the page has it.
+    let mut subresources = HashMap::new();
+    let mut images: HashMap<String, DecodedImage> = HashMap::new();
+    // The page's tree, to lay it out again as its images come in.
+    let mut page = None;
+    // Set when images have arrived that aren't laid out yet.
+    let mut images_arrived = false;
+    // Whether some of the page has been shown while it was still downloading.
+    let mut partial_shown = false;
+
     let mut now = Instant::now();
 
     let res = event_loop.run(|event, elwt| {
+        let event = match event {
+            Event::UserEvent(fetch_event) => {
+                match fetch_event {
+                    FetchEvent::Started {
+                        destination: Destination::Document,
+                        url,
+                        ..
+                    } => {
+                        window.set_title(&format!("Loading {} ({} pending)", url, pending));
+                        loading_url = url;
+                    }
+                    FetchEvent::Started { .. } => {}
+                    FetchEvent::Progress {
+                        id,
+                        received,
+                        total,
+                    } if id == document_id => {
+                        let progress = match total {
+                            Some(total) => format!("{} of {} KiB", received / 1024, total / 1024),
+                            None => format!("{} KiB", received / 1024),
+                        };
+                        window.set_title(&format!("Loading {} ({})", loading_url, progress));
+                    }
+                    FetchEvent::Progress { .. } => {}
+                    FetchEvent::Partial { id, body } if id == document_id => {
+                        layout.replace_body(*body);
+                        partial_shown = true;
+                        window.request_redraw();
+                    }
+                    FetchEvent::Partial { .. } => {}
+                    FetchEvent::Finished {
+                        destination: Destination::Document,
+                        result,
+                        ..
+                    } => {
+                        pending -= 1;
+                        let body = match result.map(|fetched| *fetched) {
+                            Ok(Fetched { url, content, .. }) => {
+                                loading_url = url.build();
+                                let body = match content {
+                                    Content::Page {
+                                        document,
+                                        body,
+                                        subresources: wanted,
+                                    } => {
+                                        for (destination, href, subresource) in wanted {
+                                            let id = fetcher.fetch(subresource, destination);
+                                            subresources.insert(id, href);
+                                            pending += 1;
+                                        }
+                                        page = Some(document);
+                                        Some(body)
+                                    }
+                                    Content::Other(body) => body,
+                                    Content::Image(_) | Content::Style => None,
+                                };
+                                window.set_title(&title(&loading_url, pending));
+                                body
+                            }
+                            Err(e) => {
+                                window.set_title("Failed to load page");
+                                Some(Body::plain(e))
+                            }
+                        };
+                        // Stay where we are if the page was already being read while it loaded.
+                        match body {
+                            Some(body) if partial_shown => layout.replace_body(body),
+                            Some(body) => layout.set_body(body),
+                            None => {}
+                        }
+                        window.request_redraw();
+                    }
+                    FetchEvent::Finished { id, result, .. } => {
+                        pending -= 1;
+                        window.set_title(&title(&loading_url, pending));
+                        let href = subresources.remove(&id).unwrap_or_default();
+                        match result.map(|fetched| fetched.content) {
+                            Ok(Content::Image(image)) => {
+                                images.insert(href, image);
+                                images_arrived = true;
+                            }
+                            Ok(_) => {}
+                            Err(e) => eprintln!("Failed to load {}: {}", href, e),
+                        }
+                    }
+                }
+                return;
+            }
+            event => event,
+        };
+
+        // Images that arrived since last time round get laid out in one go.
+        if matches!(event, Event::AboutToWait)
+            && std::mem::take(&mut images_arrived)
+            && let Some(document) = &page
+        {
+            layout.replace_body(Body::from_document(document, &images));
+            window.request_redraw();
+        }
+
         // Draw the current frame
         if let Event::WindowEvent {
             event: WindowEvent::RedrawRequested,
@@ -59,7 +186,7 @@ pub fn init_renderer(body: Body) -> Result<(), Error> {
                 dst[3] = (src >> 24) as u8;
             }
 
-            if let Err(_) = pixels.render() {
+            if pixels.render().is_err() {
                 elwt.exit();
                 return;
             }
@@ -109,3 +236,12 @@ pub fn init_renderer(body: Body) -> Result<(), Error> {
     });
     res.map_err(|e| Error::UserDefined(Box::new(e)))
 }
+
+/// The window title once the page is there, saying if its subresources are still coming.
+fn title(url: &str, pending: usize) -> String {
+    if pending > 0 {
+        format!("{} ({} pending)", url, pending)
+    } else {
+        url.to_string()
+    }
+}
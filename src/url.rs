@@ -13,70 +13,86 @@
 
 use std::{
     collections::HashMap,
-    io::{Read, Write},
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
-    sync::Arc,
+    io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write},
+    net::{IpAddr, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
 };
 
 use rustls::RootCertStore;
 use socket2::{Domain, Protocol, Socket, Type};
 
 use crate::{
+    dns, download, encoding,
+    fetch::Destination,
+    hsts,
+    html::{HTMLParser, dom::Document},
     layout::text::Body,
+    mime::{self, DocumentKind, MediaType},
+    netlog,
+    referrer::{self, ReferrerPolicy},
     renderer::init_renderer,
 };
 
 pub fn load(url: &str) -> Result<(), String> {
-    let mut url = URL::from_string(url)?;
-    let mut response = url
+    let url = URL::from_string(url)?;
+    // The window opens straight away, the page itself is fetched in the background.
+    init_renderer(url).map_err(|e| format!("Failed to run renderer: {}", e))
+}
+
+/// Request the URL and follow any redirects, returning the final URL along with its response.
+/// `progress` gets told how much of the body has arrived, see `into_response_with_progress`.
+pub fn fetch(
+    url: URL,
+    progress: impl FnMut(usize, Option<usize>),
+) -> Result<(URL, Response), String> {
+    let (url, reader) = open_following_redirects(url)?;
+    let response = reader
+        .into_response_with_progress(progress)
+        .map_err(|e| format!("Failed to load URL: {}", e))?;
+
+    if response.get_response_code() <= Some(200) || response.get_response_code() > Some(300) {
+        // Cache response
+    }
+
+    Ok((url, response))
+}
+
+/// Request the URL and follow any redirects, stopping once the final response's head is in so
+/// its body can be read as it arrives.
+pub fn open_following_redirects(mut url: URL) -> Result<(URL, ResponseReader), String> {
+    let mut reader = url
         .clone()
-        .request()
+        .open()
         .map_err(|e| format!("Failed to load URL: {}", e))?;
 
     // Redirect handling
     let mut limit = 10; // Prevent infinite redirects
-    while (response.get_response_code() <= Some(200) || response.get_response_code() >= Some(300))
+    while let Some(300..=399) = reader.status_code()
         && limit > 0
     {
-        match response.get_response_code() {
-            Some(code) if (300..400).contains(&code) => {
-                let redirect_url = response
-                    .headers
-                    .get("Location")
-                    .ok_or_else(|| "No Location header found for redirect".to_string())?;
-                // If is part of the same domain, we can just change the path
-                if !redirect_url.contains("://") {
-                    url.path = redirect_url.to_string();
-                } else {
-                    url = URL::from_string(
-                        response
-                            .headers
-                            .get("Location")
-                            .ok_or_else(|| "No Location header found for redirect".to_string())?,
-                    )
-                    .map_err(|e| format!("Failed to parse redirect URL: {}", e))?;
-                }
-            }
-            Some(_) => break,
-            None => break,
+        let location = reader
+            .header("Location")
+            .ok_or_else(|| "No Location header found for redirect".to_string())?;
+        // The redirect gets a say in the referrer policy for where it sends us.
+        if let Some(policy) = reader
+            .header("Referrer-Policy")
+            .and_then(ReferrerPolicy::from_header)
+        {
+            url.referrer_policy = policy;
         }
-        response = url
+        url = url.redirect_to(location)?;
+        // Nobody wants the redirect's body, dropping the reader just logs it.
+        reader = url
             .clone()
-            .request()
+            .open()
             .map_err(|e| format!("Failed to load URL after redirect: {}", e))?;
         limit -= 1;
     }
 
-    if response.get_response_code() <= Some(200) || response.get_response_code() > Some(300) {
-        // Cache response
-    }
-
-    if url.show_source {
-        response.display_source();
-    } else {
-        response.display();
-    }
-    Ok(())
+    Ok((url, reader))
 }
 
 #[derive(Debug, Clone)]
@@ -89,63 +105,302 @@ pub struct URL {
     pub port: u16,
     method: Method,
     show_source: bool,
+    /// Reading a body bigger than this fails instead of eating all our memory.
+    pub max_body_size: usize,
+    /// The page that asked for this URL, which decides the Referer and Origin headers.
+    referrer: Option<Box<URL>>,
+    pub referrer_policy: ReferrerPolicy,
 }
 
 impl URL {
     pub fn request(self) -> Result<Response, String> {
+        self.open()?.into_response()
+    }
+
+    /// Start the request and return as soon as the head of the response has arrived. The body can
+    /// then be read a chunk at a time from the returned reader as it comes in.
+    pub fn open(self) -> Result<ResponseReader, String> {
         match &self.scheme {
-            Scheme::Http => self.request_http(),
-            Scheme::Https => self.request_https(),
+            Scheme::Http | Scheme::Https => {
+                let mut log = netlog::PendingEntry::start(self.method.as_str(), &self.build());
+                let result = if self.scheme == Scheme::Https {
+                    self.request_https(&mut log)
+                } else {
+                    self.request_http(&mut log)
+                };
+                let mut reader = match result {
+                    Ok(reader) => reader,
+                    Err(e) => {
+                        if let Some(log) = log {
+                            log.finish(0, Some(e.clone()));
+                        }
+                        return Err(e);
+                    }
+                };
+
+                // Only believe the header when it came over HTTPS.
+                if let Some(header) = reader
+                    .header("Strict-Transport-Security")
+                    .filter(|_| self.scheme == Scheme::Https)
+                {
+                    hsts::store().observe(self.hostname(), header);
+                }
+                if let Some(mut log) = log {
+                    log.set_response(&reader.status, reader.headers.iter());
+                    reader.log = Some(log);
+                }
+                Ok(reader)
+            }
+            Scheme::Ws | Scheme::Wss => Err(format!(
+                "{} is a WebSocket, it has to be connected to rather than fetched",
+                self.build()
+            )),
             Scheme::File => self.request_file(),
             Scheme::Data(s) => URL::request_data(s.to_string()),
             _ => self.request_blank(),
         }
     }
-    fn request_http(&self) -> Result<Response, String> {
-        let s = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))
-            .map_err(|e| format!("Failed to create socket: {}", e))?;
 
-        let address: SocketAddr = format!("{}:{}", self.host, self.port)
-            .to_socket_addrs()
-            .map_err(|e| format!("Failed to resolve host: {}", e))?
-            .next()
-            .ok_or_else(|| format!("No address found for host: {}", self.host))?;
+    /// Switch an http:// (or ws://) URL over to https:// (wss://) if the host is on the HSTS list. The default port
+    /// moves with it, but an explicit non-default port is kept.
+    pub fn upgrade_if_hsts(&mut self) {
+        let secure = match self.scheme {
+            Scheme::Http => Scheme::Https,
+            Scheme::Ws => Scheme::Wss,
+            _ => return,
+        };
+        if hsts::store().should_upgrade(self.hostname()) {
+            self.scheme = secure;
+            if self.port == 80 {
+                self.port = 443;
+                self.host = self.hostname().to_string();
+            }
+        }
+    }
+
+    /// Work out where a redirect's Location header points. The request is still made for
+    /// whoever made the first one, so it keeps their referrer under the current policy.
+    pub fn redirect_to(&self, location: &str) -> Result<URL, String> {
+        let mut url = self
+            .resolve(location)
+            .map_err(|e| format!("Failed to parse redirect URL: {}", e))?;
+        url.show_source = self.show_source;
+        url.max_body_size = self.max_body_size;
+        if let Some(referrer) = &self.referrer {
+            url.set_referrer(referrer, self.referrer_policy);
+        }
+        // The host may have only just told us it wants HTTPS.
+        url.upgrade_if_hsts();
+        Ok(url)
+    }
+
+    /// Where a link like `href` on this page points: a full URL, or one relative to this host
+    /// or to the page's directory. The #fragment is never part of a request.
+    pub fn resolve(&self, href: &str) -> Result<URL, String> {
+        let href = href.trim();
+        let href = href.split('#').next().unwrap_or_default();
+        let absolute = if href.contains("://") || href.starts_with("data:") {
+            href.to_string()
+        } else if let Some(rest) = href.strip_prefix("//") {
+            format!("{}://{}", self.scheme.as_str(), rest)
+        } else if href.starts_with('/') {
+            format!("{}://{}{}", self.scheme.as_str(), self.host, href)
+        } else {
+            let directory = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+            format!(
+                "{}://{}{}{}",
+                self.scheme.as_str(),
+                self.host,
+                directory,
+                href
+            )
+        };
+        URL::from_string(absolute)
+    }
+
+    /// Load this on behalf of `referrer`, e.g. a link or an image on that page. `policy` is the
+    /// page's referrer policy, see `Response::referrer_policy`.
+    pub fn set_referrer(&mut self, referrer: &URL, policy: ReferrerPolicy) {
+        let mut referrer = referrer.clone();
+        referrer.referrer = None;
+        self.referrer = Some(Box::new(referrer));
+        self.referrer_policy = policy;
+    }
+
+    /// `scheme://host[:port]`, only network URLs have one.
+    pub fn origin(&self) -> Option<String> {
+        let default_port = match self.scheme {
+            Scheme::Http | Scheme::Ws => 80,
+            Scheme::Https | Scheme::Wss => 443,
+            _ => return None,
+        };
+        let host = self.hostname().to_ascii_lowercase();
+        Some(if self.port == default_port {
+            format!("{}://{}", self.scheme.as_str(), host)
+        } else {
+            format!("{}://{}:{}", self.scheme.as_str(), host, self.port)
+        })
+    }
+
+    pub fn is_secure(&self) -> bool {
+        matches!(self.scheme, Scheme::Https | Scheme::Wss)
+    }
+
+    /// Secure schemes, local files and loopback hosts, none of which count as a downgrade.
+    pub fn is_potentially_trustworthy(&self) -> bool {
+        let host = self
+            .hostname()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        self.is_secure()
+            || self.scheme == Scheme::File
+            || host.eq_ignore_ascii_case("localhost")
+            || host.to_ascii_lowercase().ends_with(".localhost")
+            || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+
+    /// The Origin header for a request made by the referrer, if there is one.
+    pub fn initiator_origin(&self) -> Option<String> {
+        let initiator = self.referrer.as_ref()?;
+        Some(referrer::origin_value(
+            self.referrer_policy,
+            initiator,
+            self,
+        ))
+    }
+
+    /// The host without any port on the end, this is what we resolve and what goes in the SNI.
+    pub fn hostname(&self) -> &str {
+        self.host.split(':').next().unwrap_or_default()
+    }
+
+    fn connect(&self, log: &mut Option<netlog::PendingEntry>) -> Result<TcpStream, String> {
+        if let Some(log) = log {
+            log.phase(netlog::Phase::Blocked);
+        }
+        let addresses = dns::resolve(self.hostname(), self.port)?;
+        if let Some(log) = log {
+            log.phase(netlog::Phase::Dns);
+        }
+
+        // Try each address in turn, a host can have IPv6 addresses we can't actually reach.
+        let mut error = format!("No address found for host: {}", self.host);
+        for address in addresses {
+            let s = Socket::new(
+                Domain::for_address(address),
+                Type::STREAM,
+                Some(Protocol::TCP),
+            )
+            .map_err(|e| format!("Failed to create socket: {}", e))?;
+            match s.connect(&address.into()) {
+                Ok(()) => {
+                    if let Some(log) = log {
+                        log.phase(netlog::Phase::Connect);
+                        log.set_server_ip(address.ip());
+                    }
+                    return Ok(s.into());
+                }
+                Err(e) => {
+                    error = format!("Failed to connect to {} / {:#?}: {}", self.host, address, e)
+                }
+            }
+        }
+        Err(error)
+    }
 
-        s.connect(&address.into())
-            .map_err(|e| format!("Failed to connect to {} / {:#?}: {}", self.host, address, e))?;
+    /// The path and query, what goes on the request line.
+    pub fn target(&self) -> String {
+        let mut target = self.path.clone();
+        if !self.queries.is_empty() {
+            target.push('?');
+            target.push_str(
+                &self
+                    .queries
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect::<Vec<_>>()
+                    .join("&"),
+            );
+        }
+        target
+    }
 
+    pub(crate) fn request_text(&self) -> String {
         // TODO: http://browser.engineering/http.html 1-6 keep-alive
-        let request = format!(
-            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept-Encoding: identity\r\n\r\n",
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept-Encoding: identity\r\n",
             self.method.as_str(),
-            self.path,
+            self.target(),
             self.host
         );
+        if let Some(referrer) = self
+            .referrer
+            .as_ref()
+            .and_then(|referrer| referrer::referrer_value(self.referrer_policy, referrer, self))
+        {
+            request.push_str(&format!("Referer: {}\r\n", referrer));
+        }
+        // Only requests that could change something on the server say where they came from.
+        if let Some(origin) = self
+            .initiator_origin()
+            .filter(|_| !matches!(self.method, Method::Get))
+        {
+            request.push_str(&format!("Origin: {}\r\n", origin));
+        }
+        for (key, value) in &self.headers {
+            request.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        request.push_str("\r\n");
+        request
+    }
 
-        s.send(request.as_bytes())
+    /// Send the request head, noting it down in the network log.
+    fn send_request(
+        &self,
+        stream: &mut impl Write,
+        log: &mut Option<netlog::PendingEntry>,
+    ) -> Result<(), String> {
+        let request = self.request_text();
+        stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.flush())
             .map_err(|e| format!("Failed to send request: {}", e))?;
+        if let Some(log) = log {
+            log.set_request(&request);
+            log.phase(netlog::Phase::Send);
+        }
+        Ok(())
+    }
 
-        let mut response = Vec::new();
-        let mut buf = [std::mem::MaybeUninit::<u8>::uninit(); 8192];
-        loop {
-            match s.recv(&mut buf) {
-                Ok(0) => break, // Connection closed
-                Ok(n) => {
-                    let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, n) };
-                    response.extend_from_slice(bytes);
-                    if n == 0 {
-                        break; // No more data
-                    }
-                }
-                Err(e) => return Err(format!("Failed to read response: {}", e)),
-            }
+    fn request_http(
+        &self,
+        log: &mut Option<netlog::PendingEntry>,
+    ) -> Result<ResponseReader, String> {
+        let mut stream = self.connect(log)?;
+        self.send_request(&mut stream, log)?;
+        self.read_response(Box::new(BufReader::new(stream)), log)
+    }
+
+    /// Wait for the first byte of the response, which ends the wait phase, then read its head.
+    fn read_response(
+        &self,
+        mut stream: Box<dyn BufRead + Send>,
+        log: &mut Option<netlog::PendingEntry>,
+    ) -> Result<ResponseReader, String> {
+        stream
+            .fill_buf()
+            .map_err(|e| format!("Failed to read response: {}", e))?;
+        if let Some(log) = log {
+            log.phase(netlog::Phase::Wait);
         }
-        let response_str = String::from_utf8_lossy(&response).to_string();
-        Response::from_string(&response_str)
-            .map_err(|e| format!("Failed to parse response: {}", e))
+        ResponseReader::from_stream(stream, self.max_body_size)
     }
 
-    fn request_https(&self) -> Result<Response, String> {
+    /// Connect and finish the TLS handshake.
+    fn connect_tls(
+        &self,
+        log: &mut Option<netlog::PendingEntry>,
+    ) -> Result<rustls::StreamOwned<rustls::ClientConnection, TcpStream>, String> {
         let root_store = RootCertStore {
             roots: webpki_roots::TLS_SERVER_ROOTS.into(),
         };
@@ -153,72 +408,104 @@ impl URL {
             .with_root_certificates(root_store)
             .with_no_client_auth();
 
-        // TODO: http://browser.engineering/http.html 1-6 keep-alive
-        let request = format!(
-            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nAccept-Encoding: identity\r\n\r\n",
-            self.method.as_str(),
-            self.path,
-            self.host
-        );
-
         // Allow using SSLKEYLOGFILE.
         config.key_log = Arc::new(rustls::KeyLogFile::new());
 
-        let mut conn =
-            rustls::ClientConnection::new(Arc::new(config), self.host.clone().try_into().unwrap())
-                .unwrap();
-        let mut sock = TcpStream::connect(format!("{}:{}", self.host, self.port)).unwrap();
-        let mut tls = rustls::Stream::new(&mut conn, &mut sock);
+        let server_name = self
+            .hostname()
+            .to_string()
+            .try_into()
+            .map_err(|e| format!("Invalid server name {}: {}", self.host, e))?;
+        let mut conn = rustls::ClientConnection::new(Arc::new(config), server_name)
+            .map_err(|e| format!("Failed to set up TLS: {}", e))?;
+        let mut sock = self.connect(log)?;
+
+        // rustls would do the handshake on the first write anyway, doing it here lets us time it.
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)
+                .map_err(|e| format!("TLS handshake with {} failed: {}", self.host, e))?;
+        }
+        if let Some(log) = log {
+            log.phase(netlog::Phase::Tls);
+        }
+        Ok(rustls::StreamOwned::new(conn, sock))
+    }
 
-        tls.write_all(request.as_bytes())
-            .map_err(|e| format!("Failed to write request: {}", e))?;
+    /// A raw connection to the host, over TLS for https:// and wss://. This is what protocols
+    /// that take over the socket after the HTTP handshake (WebSockets) build on.
+    pub(crate) fn open_stream(&self) -> Result<Box<dyn Stream>, String> {
+        match self.scheme {
+            Scheme::Http | Scheme::Ws => Ok(Box::new(self.connect(&mut None)?)),
+            Scheme::Https | Scheme::Wss => Ok(Box::new(self.connect_tls(&mut None)?)),
+            _ => Err(format!("Can't open a connection for {}", self.build())),
+        }
+    }
 
-        let mut plaintext = Vec::new();
-        tls.read_to_end(&mut plaintext).unwrap();
+    pub fn is_websocket(&self) -> bool {
+        matches!(self.scheme, Scheme::Ws | Scheme::Wss)
+    }
 
-        let response_str = String::from_utf8_lossy(&plaintext).to_string();
-        Response::from_string(&response_str)
-            .map_err(|e| format!("Failed to parse response: {}", e))
+    fn request_https(
+        &self,
+        log: &mut Option<netlog::PendingEntry>,
+    ) -> Result<ResponseReader, String> {
+        let mut tls = self.connect_tls(log)?;
+        self.send_request(&mut tls, log)?;
+        self.read_response(Box::new(BufReader::new(tls)), log)
     }
 
-    fn request_file(&self) -> Result<Response, String> {
+    fn request_file(&self) -> Result<ResponseReader, String> {
         let path = &self.path;
-        let mut file = std::fs::File::open(path)
+        let file = std::fs::File::open(path)
             .map_err(|e| format!("Failed to open file {}: {}", path, e))?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .map_err(|e| format!("Failed to read file {}: {}", path, e))?;
-        Ok(Response::new(
+        let size = file
+            .metadata()
+            .map_err(|e| format!("Failed to read file {}: {}", path, e))?
+            .len() as usize;
+        // Local files get their type from their extension, anything else gets sniffed.
+        let mut headers = HashMap::new();
+        headers.insert("Content-Length".to_string(), size.to_string());
+        if let Some(media_type) = mime::from_extension(path) {
+            headers.insert("Content-Type".to_string(), media_type.to_string());
+        }
+        ResponseReader::from_parts(
             "200 OK".to_string(),
-            HashMap::new(),
-            Body::new(contents),
-        ))
+            headers,
+            Box::new(BufReader::new(file)),
+            self.max_body_size,
+        )
     }
 
     /// This handles data e.g:
     /// data:text/html,<html><head><title>Hello</title></head><body><h1>Hello, world!</h1><p>This is a <strong>bold</strong> paragraph with <em>italic</em> text and a <a href='https://example.com'>link</a>.</p><ul><li>List item 1</li><li>List item 2</li></ul></body></html>
-    /// We currently only support text/html data.
-    fn request_data(s: String) -> Result<Response, String> {
-        s.split_once(',')
-            .map(|(_, data)| {
-                Response::new(
-                    "200 OK".to_string(),
-                    HashMap::new(),
-                    Body::new(data.to_string()),
-                )
-            })
-            .ok_or_else(|| "Invalid data URL format".to_string())
+    /// The media type before the comma becomes the Content-Type, base64 data isn't supported yet.
+    fn request_data(s: String) -> Result<ResponseReader, String> {
+        let (media_type, data) = s
+            .split_once(',')
+            .ok_or_else(|| "Invalid data URL format".to_string())?;
+        let mut headers = HashMap::new();
+        if !media_type.is_empty() {
+            headers.insert("Content-Type".to_string(), media_type.to_string());
+        }
+        ResponseReader::from_parts(
+            "200 OK".to_string(),
+            headers,
+            Box::new(Cursor::new(data.as_bytes().to_vec())),
+            usize::MAX,
+        )
     }
 
     // This handles about:blank
-    fn request_blank(&self) -> Result<Response, String> {
+    fn request_blank(&self) -> Result<ResponseReader, String> {
         // Handle about:blank or view-source: URLs
         if self.scheme == Scheme::AboutBlank {
-            Ok(Response::new(
+            let headers = HashMap::from([("Content-Type".to_string(), "text/html".to_string())]);
+            ResponseReader::from_parts(
                 "200 OK".to_string(),
-                HashMap::new(),
-                Body::new("<html><body></body></html>".to_string()),
-            ))
+                headers,
+                Box::new(Cursor::new(b"<html><body></body></html>".to_vec())),
+                usize::MAX,
+            )
         } else {
             Err("Unsupported URL scheme for request".to_string())
         }
@@ -226,6 +513,7 @@ impl URL {
 }
 
 impl URL {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         scheme: &Scheme,
         host: impl std::fmt::Display,
@@ -245,6 +533,9 @@ impl URL {
             queries: queries.clone(),
             headers: headers.clone(),
             show_source,
+            max_body_size: MAX_BODY_SIZE.load(Ordering::Relaxed),
+            referrer: None,
+            referrer_policy: ReferrerPolicy::default(),
         }
     }
 
@@ -260,7 +551,7 @@ impl URL {
             // Edge cases for testing urls
             if url.starts_with("data:") {
                 return Ok(URL::new(
-                    &Scheme::Data(url.split(':').nth(1).unwrap_or("").to_string()),
+                    &Scheme::Data(url.trim_start_matches("data:").to_string()),
                     "",
                     "",
                     0,               // Port is not applicable for data URLs",
@@ -327,12 +618,12 @@ impl URL {
                     .map_err(|_| "Invalid port number".to_string())?
             } else {
                 match scheme {
-                    Scheme::Http => 80,   // Default port for HTTP
-                    Scheme::Https => 443, // Default port for HTTPS
-                    _ => 0,               // Default to 0 for other schemes
+                    Scheme::Http | Scheme::Ws => 80,    // Default port for HTTP
+                    Scheme::Https | Scheme::Wss => 443, // Default port for HTTPS
+                    _ => 0,                             // Default to 0 for other schemes
                 }
             };
-            Ok(URL::new(
+            let mut url = URL::new(
                 &scheme,
                 host,
                 path,
@@ -341,7 +632,9 @@ impl URL {
                 &queries,
                 &HashMap::new(), // headers empty by default
                 show_source,
-            ))
+            );
+            url.upgrade_if_hsts();
+            Ok(url)
         }
     }
 
@@ -362,59 +655,500 @@ impl URL {
     }
 }
 
+/// Anything we can talk a protocol over, a plain TCP socket or a TLS stream.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// How big a response body can get before we give up on it, unless changed with `set_max_body_size`.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+/// The head has to fit in this, anything bigger is almost certainly not HTTP.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const CHUNK_SIZE: usize = 8192;
+
+static MAX_BODY_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_BODY_SIZE);
+
+/// Change the body size limit for every URL created from now on.
+pub fn set_max_body_size(bytes: usize) {
+    MAX_BODY_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+/// How the end of the body is marked.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    /// Content-Length, with how many bytes are still to come.
+    Length(usize),
+    /// Transfer-Encoding: chunked, with how much of the current chunk is left.
+    Chunked {
+        remaining: usize,
+    },
+    /// No length given, the body runs until the connection closes.
+    UntilClose,
+    Done,
+}
+
+/// A response whose head has been read but whose body is still on the wire.
+/// `read_chunk` hands the body over piece by piece as it arrives.
+pub struct ResponseReader {
+    pub status: String,
+    pub headers: HashMap<String, String>,
+    stream: Box<dyn BufRead + Send>,
+    framing: Framing,
+    received: usize,
+    max_body_size: usize,
+    /// Network log entry, finished off once the body has been read.
+    log: Option<netlog::PendingEntry>,
+}
+
+impl ResponseReader {
+    /// Read the status line and headers off an HTTP stream.
+    fn from_stream(
+        mut stream: Box<dyn BufRead + Send>,
+        max_body_size: usize,
+    ) -> Result<Self, String> {
+        let (status, headers) = read_head(&mut stream)?;
+        Self::from_parts(status, headers, stream, max_body_size)
+    }
+
+    /// Wrap a body stream with a head we already have, e.g. for files and data URLs.
+    fn from_parts(
+        status: String,
+        headers: HashMap<String, String>,
+        stream: Box<dyn BufRead + Send>,
+        max_body_size: usize,
+    ) -> Result<Self, String> {
+        let chunked = find_header(&headers, "Transfer-Encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        let code = status.split(' ').nth(1).and_then(|c| c.parse::<u16>().ok());
+
+        let framing = if matches!(code, Some(100..=199 | 204 | 304)) {
+            Framing::Done
+        } else if chunked {
+            Framing::Chunked { remaining: 0 }
+        } else if let Some(length) = find_header(&headers, "Content-Length") {
+            let length = length
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid Content-Length: {}", length))?;
+            // No point downloading something we already know we'll throw away.
+            if length > max_body_size {
+                return Err(body_too_large(max_body_size));
+            }
+            Framing::Length(length)
+        } else {
+            Framing::UntilClose
+        };
+
+        Ok(ResponseReader {
+            status,
+            headers,
+            stream,
+            framing,
+            received: 0,
+            max_body_size,
+            log: None,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn status_code(&self) -> Option<u16> {
+        self.status
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+    }
+
+    /// The full size of the body if the server told us it.
+    pub fn content_length(&self) -> Option<usize> {
+        match self.framing {
+            Framing::Chunked { .. } => None,
+            _ => self.header("Content-Length")?.trim().parse().ok(),
+        }
+    }
+
+    /// A parser to feed the body to as it arrives, if it's a page we'll show as HTML. Only goes
+    /// by the Content-Type, anything that needs sniffing waits for the whole body.
+    pub fn html_parser(&self, url: &URL) -> Option<HTMLParser> {
+        let content_type = self.header("Content-Type")?;
+        let html = MediaType::parse(content_type)
+            .is_some_and(|media_type| media_type.document_kind() == DocumentKind::Html);
+        (html && !url.show_source).then(|| HTMLParser::streaming(Some(content_type)))
+    }
+
+    /// How many bytes of the body have been read so far.
+    pub fn received(&self) -> usize {
+        self.received
+    }
+
+    /// Read the next piece of the body, or None once it's all arrived.
+    pub fn read_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let result = self.next_chunk();
+        match &result {
+            Ok(Some(_)) => {}
+            Ok(None) => self.finish_log(None),
+            Err(e) => self.finish_log(Some(e.clone())),
+        }
+        result
+    }
+
+    /// Give up on the rest of the body on purpose, e.g. to ask for it again differently. Unlike
+    /// just dropping the reader, that doesn't count as an error in the network log.
+    pub fn abandon(mut self) {
+        self.finish_log(None);
+    }
+
+    fn finish_log(&mut self, error: Option<String>) {
+        if let Some(mut log) = self.log.take() {
+            log.phase(netlog::Phase::Receive);
+            log.finish(self.received, error);
+        }
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        let want = match self.framing {
+            Framing::Done => return Ok(None),
+            Framing::Length(0) => {
+                self.framing = Framing::Done;
+                return Ok(None);
+            }
+            Framing::Length(remaining) => remaining.min(CHUNK_SIZE),
+            Framing::Chunked { remaining: 0 } => match self.next_chunk_size()? {
+                0 => {
+                    self.framing = Framing::Done;
+                    return Ok(None);
+                }
+                size => {
+                    self.framing = Framing::Chunked { remaining: size };
+                    size.min(CHUNK_SIZE)
+                }
+            },
+            Framing::Chunked { remaining } => remaining.min(CHUNK_SIZE),
+            Framing::UntilClose => CHUNK_SIZE,
+        };
+
+        let mut buf = vec![0; want];
+        let n = match self.stream.read(&mut buf) {
+            Ok(n) => n,
+            // Plenty of servers close TLS connections without a close_notify. That's only a
+            // problem if we were still expecting more bytes, which the checks below catch.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(format!("Failed to read response: {}", e)),
+        };
+        buf.truncate(n);
+
+        self.framing = match self.framing {
+            _ if n == 0 && self.framing == Framing::UntilClose => Framing::Done,
+            _ if n == 0 => {
+                return Err("Connection closed before the whole body arrived".to_string());
+            }
+            Framing::Length(remaining) => Framing::Length(remaining - n),
+            Framing::Chunked { remaining } => {
+                if remaining == n {
+                    // Every chunk is followed by a CRLF.
+                    let mut crlf = String::new();
+                    let _ = self.stream.read_line(&mut crlf);
+                }
+                Framing::Chunked {
+                    remaining: remaining - n,
+                }
+            }
+            framing => framing,
+        };
+        if n == 0 {
+            return Ok(None);
+        }
+
+        self.received += n;
+        if self.received > self.max_body_size {
+            return Err(body_too_large(self.max_body_size));
+        }
+        Ok(Some(buf))
+    }
+
+    /// Read a chunk size line, and the trailers after the last chunk.
+    fn next_chunk_size(&mut self) -> Result<usize, String> {
+        let mut line = String::new();
+        self.stream
+            .read_line(&mut line)
+            .map_err(|e| format!("Failed to read chunk size: {}", e))?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| format!("Invalid chunk size: {}", line.trim()))?;
+        if size == 0 {
+            loop {
+                line.clear();
+                match self.stream.read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) if line.trim().is_empty() => break,
+                    Ok(_) => {}
+                }
+            }
+        }
+        Ok(size)
+    }
+
+    /// Read the rest of the body and return the complete response.
+    pub fn into_response(self) -> Result<Response, String> {
+        self.into_response_with_progress(|_, _| {})
+    }
+
+    /// Like `into_response`, calling `progress` with the bytes received so far (and the total
+    /// if we know it) every time a piece of the body arrives.
+    pub fn into_response_with_progress(
+        self,
+        mut progress: impl FnMut(usize, Option<usize>),
+    ) -> Result<Response, String> {
+        self.into_response_with_chunks(|_, received, total| progress(received, total))
+    }
+
+    /// Like `into_response_with_progress`, also handing over each piece of the body, e.g. to
+    /// parse a page while the rest of it downloads.
+    pub fn into_response_with_chunks(
+        mut self,
+        mut on_chunk: impl FnMut(&[u8], usize, Option<usize>),
+    ) -> Result<Response, String> {
+        let total = self.content_length();
+        let mut content = Vec::new();
+        while let Some(chunk) = self.read_chunk()? {
+            content.extend_from_slice(&chunk);
+            on_chunk(&chunk, self.received, total);
+        }
+        let status = std::mem::take(&mut self.status);
+        let headers = std::mem::take(&mut self.headers);
+        Ok(Response::new(status, headers, content))
+    }
+}
+
+impl Drop for ResponseReader {
+    fn drop(&mut self) {
+        // Still log requests whose body was never read to the end. Nobody reads a redirect's
+        // body though, following it is what counts.
+        if self.framing != Framing::Done {
+            let redirect =
+                matches!(self.status_code(), Some(300..=399)) && self.header("Location").is_some();
+            let error = (!redirect).then(|| "Body was not read to the end".to_string());
+            self.finish_log(error);
+        }
+    }
+}
+
+/// Read a response's status line and headers, leaving the stream at the start of the body.
+pub(crate) fn read_head(
+    stream: &mut impl BufRead,
+) -> Result<(String, HashMap<String, String>), String> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let n = stream
+            .read_until(b'\n', &mut head)
+            .map_err(|e| format!("Failed to read response: {}", e))?;
+        if n == 0 {
+            break; // Connection closed
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(format!(
+                "Response headers are larger than {} bytes",
+                MAX_HEAD_SIZE
+            ));
+        }
+        // A blank line ends the head (the first line can't be blank though).
+        if start > 0 && matches!(&head[start..], b"\r\n" | b"\n") {
+            break;
+        }
+    }
+
+    // Header values are bytes, so read them as latin1.
+    let head: String = head.iter().map(|&b| b as char).collect();
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .filter(|line| !line.trim().is_empty())
+        .ok_or("Empty response")?
+        .trim_end()
+        .to_string();
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((key, value)) = line.split_once(':') {
+            headers.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+    Ok((status, headers))
+}
+
+fn body_too_large(max_body_size: usize) -> String {
+    format!(
+        "Response body is larger than the maximum of {} bytes",
+        max_body_size
+    )
+}
+
+/// Header names are case-insensitive, so look them up ignoring case.
+pub(crate) fn find_header<'a>(headers: &'a HashMap<String, String>, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
 #[derive(Debug, Clone)]
 pub struct Response {
     pub status: String,
     pub headers: HashMap<String, String>,
-    pub body: Body,
+    /// The raw body, it only gets decoded once we know what sort of document it is.
+    pub content: Vec<u8>,
 }
 
 impl Response {
-    pub fn new(status: String, headers: HashMap<String, String>, body: Body) -> Self {
+    pub fn new(status: String, headers: HashMap<String, String>, content: Vec<u8>) -> Self {
         Response {
             status,
             headers,
-            body,
+            content,
         }
     }
 
-    pub fn from_string(response: &str) -> Result<Self, String> {
-        let mut lines = response.lines();
-        let status_line = lines.next().ok_or("Empty response")?;
-        let status = status_line.to_string();
-        let mut headers = HashMap::new();
-        let mut body = String::new();
-        let mut headers_done = false;
+    /// Parse a raw HTTP response that's already fully in memory.
+    pub fn from_bytes(response: &[u8]) -> Result<Self, String> {
+        let stream = Box::new(Cursor::new(response.to_vec()));
+        ResponseReader::from_stream(stream, usize::MAX)?.into_response()
+    }
 
-        for line in lines {
-            if line.is_empty() {
-                headers_done = true;
-                continue; // Empty line indicates end of headers
-            }
-            if headers_done {
-                body.push_str(line);
-                continue; // Collect body after headers
-            }
-            if let Some((key, value)) = line.split_once(':') {
-                headers.insert(key.trim().to_string(), value.trim().to_string());
-            }
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// The referrer policy for loads made by this response's `document`: the Referrer-Policy
+    /// header, then overridden by any `<meta name="referrer">`.
+    pub fn referrer_policy(&self, document: &Document) -> ReferrerPolicy {
+        let policy = self
+            .header("Referrer-Policy")
+            .and_then(ReferrerPolicy::from_header)
+            .unwrap_or_default();
+        ReferrerPolicy::from_meta(document).unwrap_or(policy)
+    }
+
+    /// The media type from the Content-Type header, or a sniffed one if there isn't a usable header.
+    pub fn media_type(&self) -> MediaType {
+        let nosniff = self
+            .header("X-Content-Type-Options")
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case("nosniff"));
+        mime::resolve(self.header("Content-Type"), nosniff, &self.content)
+    }
+
+    /// The body decoded to text using the charset we detect for it.
+    pub fn text(&self) -> String {
+        encoding::decode(&self.content, self.header("Content-Type")).0
+    }
+
+    /// Turn the response into a document for the renderer. Anything we can't show is offered as
+    /// a download instead (on its own thread, as it waits on stdin) and we return None.
+    pub fn document(&self, url: &URL) -> Option<Body> {
+        if url.show_source {
+            return Some(Body::plain(self.text()));
+        }
+
+        let media_type = self.media_type();
+        match media_type.document_kind() {
+            DocumentKind::Html => return Some(Body::new(self.text())),
+            DocumentKind::PlainText => return Some(Body::plain(self.text())),
+            DocumentKind::Image => match Body::image(&self.content) {
+                Ok(body) => return Some(body),
+                Err(e) => eprintln!("{}", e),
+            },
+            DocumentKind::Download => {}
         }
 
-        Ok(Response::new(status, headers, Body::new(body)))
+        let (response, url) = (self.clone(), url.clone());
+        std::thread::spawn(move || response.offer_download(&url, &media_type));
+        None
+    }
+
+    /// The tree of an HTML page at `url`, or None if it's something else or we're showing its
+    /// source. Anything that's not a page goes through `document` instead. `streamed` is the
+    /// parser the body was fed to as it arrived, see `ResponseReader::html_parser`. It gets
+    /// finished off rather than starting again, unless the encoding it guessed from the start of
+    /// the body turns out wrong for the whole of it.
+    pub fn html_document(&self, url: &URL, streamed: Option<HTMLParser>) -> Option<Document> {
+        if url.show_source || self.media_type().document_kind() != DocumentKind::Html {
+            return None;
+        }
+        if let Some(mut parser) = streamed {
+            parser.finish();
+            let encoding = encoding::detect_encoding(&self.content, self.header("Content-Type"));
+            if parser.encoding() == Some(encoding) {
+                return Some(parser.into_document());
+            }
+        }
+        Some(Document::parse(&self.text()))
     }
 
-    pub fn display(&self) {
-        let _ = init_renderer(self.body.clone());
+    /// The stylesheets and images the page at `url` uses, with their `href` or `src` as written
+    /// and where that points. `document` is its tree, see `html_document`. They're loaded on the
+    /// page's behalf, so under its referrer policy.
+    pub fn subresources(&self, url: &URL, document: &Document) -> Vec<(Destination, String, URL)> {
+        let policy = self.referrer_policy(document);
+        let stylesheets = document
+            .get_elements_by_tag_name("link")
+            .into_iter()
+            .filter_map(|id| document.element(id))
+            .filter(|link| {
+                link.attribute("rel").is_some_and(|rel| {
+                    rel.split_ascii_whitespace()
+                        .any(|rel| rel.eq_ignore_ascii_case("stylesheet"))
+                })
+            })
+            .filter_map(|link| Some((Destination::Style, link.attribute("href")?)));
+        let images = document
+            .get_elements_by_tag_name("img")
+            .into_iter()
+            .filter_map(|id| Some((Destination::Image, document.element(id)?.attribute("src")?)));
+        stylesheets
+            .chain(images)
+            .filter(|(_, href)| !href.trim().is_empty())
+            .filter_map(|(destination, href)| {
+                let mut subresource = url.resolve(href).ok()?;
+                subresource.set_referrer(url, policy);
+                Some((destination, href.to_string(), subresource))
+            })
+            .collect()
     }
 
-    pub fn display_source(&self) {
-        let _ = init_renderer(self.body.clone());
+    /// We can't show this, so ask whether to save it to the current directory instead.
+    fn offer_download(&self, url: &URL, media_type: &MediaType) {
+        let name = download::filename(self.header("Content-Disposition"), url);
+        print!(
+            "Save {} ({}, {} bytes)? [y/N] ",
+            name,
+            media_type.essence(),
+            self.content.len()
+        );
+        let _ = std::io::stdout().flush();
+
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer).is_err()
+            || !answer.trim().eq_ignore_ascii_case("y")
+        {
+            return;
+        }
+
+        // Don't clobber anything that's already there.
+        let mut path = std::path::PathBuf::from(&name);
+        let mut n = 1;
+        while path.exists() {
+            path = std::path::PathBuf::from(format!("{} ({})", name, n));
+            n += 1;
+        }
+        match std::fs::write(&path, &self.content) {
+            Ok(()) => println!("Saved {}", path.display()),
+            Err(e) => eprintln!("Failed to save {}: {}", path.display(), e),
+        }
     }
 
     pub fn get_response_code(&self) -> Option<u16> {
-        
-        self
-            .status
+        self.status
             .split(" ")
             .nth(1)
             .and_then(|code| code.parse::<u16>().ok())
@@ -446,6 +1180,8 @@ impl Method {
 enum Scheme {
     Http,
     Https,
+    Ws,
+    Wss,
     File,
     Data(String), // Data URLs can have a specific type, e.g., "text/html"
     ViewSource,
@@ -457,6 +1193,8 @@ impl Scheme {
         match self {
             Scheme::Http => "http",
             Scheme::Https => "https",
+            Scheme::Ws => "ws",
+            Scheme::Wss => "wss",
             Scheme::File => "file",
             Scheme::Data(_) => "data",
             Scheme::ViewSource => "view-source",
@@ -467,6 +1205,8 @@ impl Scheme {
         match s {
             "http" => Some(Scheme::Http),
             "https" => Some(Scheme::Https),
+            "ws" => Some(Scheme::Ws),
+            "wss" => Some(Scheme::Wss),
             "file" => Some(Scheme::File),
             "data" => Some(Scheme::Data(String::new())), // Default to empty data type
             "view-source" => Some(Scheme::ViewSource),   // Default to empty data type
@@ -475,3 +1215,183 @@ impl Scheme {
         }
     }
 }
+
+#[test]
+fn test_response_reader_framing_and_limits() {
+    let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7;ext=1\r\n, world\r\n0\r\n\r\n";
+    let response = Response::from_bytes(chunked).unwrap();
+    assert_eq!(response.content, b"Hello, world");
+
+    // Anything past the Content-Length is ignored.
+    let sized = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nHello, world";
+    assert_eq!(Response::from_bytes(sized).unwrap().content, b"Hello");
+
+    // Over the limit, both when the length is announced and when it's only found out by reading.
+    let stream = Box::new(Cursor::new(sized.to_vec()));
+    assert!(ResponseReader::from_stream(stream, 4).is_err());
+    let until_close = b"HTTP/1.1 200 OK\r\n\r\nHello, world".to_vec();
+    let mut reader = ResponseReader::from_stream(Box::new(Cursor::new(until_close)), 4).unwrap();
+    assert_eq!(reader.read_chunk(), Err(body_too_large(4)));
+
+    // Pages get parsed as they arrive, but only when the header says they're HTML.
+    let reader = |head: &str| {
+        let bytes = format!("HTTP/1.1 200 OK\r\n{}\r\n", head).into_bytes();
+        ResponseReader::from_stream(Box::new(Cursor::new(bytes)), 100).unwrap()
+    };
+    let page = URL::from_string("http://example.com/").unwrap();
+    let source = URL::from_string("view-source:http://example.com/").unwrap();
+    let html = reader("Content-Type: text/html; charset=utf-8\r\n");
+    assert!(html.html_parser(&page).is_some());
+    assert!(html.html_parser(&source).is_none());
+    assert!(
+        reader("Content-Type: text/plain\r\n")
+            .html_parser(&page)
+            .is_none()
+    );
+    assert!(reader("").html_parser(&page).is_none());
+}
+
+#[test]
+fn test_streamed_page_encoding() {
+    // Only the start of the body is UTF-8, so the streamed parse guessed wrong and the page is
+    // parsed again to come out the same as it would in one go.
+    let mut body = "<p>".repeat(400).into_bytes();
+    body.extend_from_slice(b"<p>caf\xE9 au lait");
+    let mut raw = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n".to_vec();
+    raw.extend_from_slice(&body);
+    let reader = ResponseReader::from_stream(Box::new(Cursor::new(raw)), usize::MAX).unwrap();
+    let page = URL::from_string("http://example.com/").unwrap();
+    let mut parser = reader.html_parser(&page).unwrap();
+    let response = reader
+        .into_response_with_chunks(|chunk, _, _| {
+            parser.feed(chunk);
+        })
+        .unwrap();
+
+    let document = response.html_document(&page, Some(parser)).unwrap();
+    let body = document.get_elements_by_tag_name("body")[0];
+    assert!(document.text_content(body).ends_with("caf\u{e9} au lait"));
+    let expected = response.html_document(&page, None).unwrap();
+    assert_eq!(document.to_html(), expected.to_html());
+}
+
+#[test]
+fn test_local_file_types() {
+    // Local files are HTML by their extension, even ones the sniffer wouldn't spot.
+    let dir = std::env::temp_dir();
+    let page = dir.join(format!("browser-engineering-{}.html", std::process::id()));
+    std::fs::write(&page, "<ul><li>one</ul>").unwrap();
+    let url = URL::from_string(format!("file://{}", page.display())).unwrap();
+    let response = url.open().and_then(ResponseReader::into_response);
+    std::fs::remove_file(&page).unwrap();
+    let response = response.unwrap();
+    assert_eq!(response.header("Content-Type"), Some("text/html"));
+    assert_eq!(response.media_type().document_kind(), DocumentKind::Html);
+}
+
+#[test]
+fn test_subresources() {
+    let page = URL::from_string("http://example.com/docs/page.html?x=1").unwrap();
+    let resolved = |href: &str| page.resolve(href).unwrap().build();
+    assert_eq!(resolved("style.css"), "http://example.com/docs/style.css");
+    assert_eq!(resolved("/img/a.png#top"), "http://example.com/img/a.png");
+    assert_eq!(
+        resolved("//cdn.example.org/x.js"),
+        "http://cdn.example.org/x.js"
+    );
+    assert_eq!(resolved("https://other.org/"), "https://other.org/");
+
+    let html = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n\
+        <link rel='Alternate Stylesheet' href=theme.css><link rel=icon href=favicon.ico>\
+        <p><img src=/logo.png alt=logo><img alt=nothing><img src=''>";
+    let response = Response::from_bytes(html).unwrap();
+    let document = response.html_document(&page, None).unwrap();
+    let found: Vec<(Destination, String, String)> = response
+        .subresources(&page, &document)
+        .into_iter()
+        .map(|(destination, href, url)| (destination, href, url.build()))
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (
+                Destination::Style,
+                "theme.css".to_string(),
+                "http://example.com/docs/theme.css".to_string()
+            ),
+            (
+                Destination::Image,
+                "/logo.png".to_string(),
+                "http://example.com/logo.png".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_subresource_referrer() {
+    let page = URL::from_string("https://example.com/private/page?id=1").unwrap();
+    let subresource = |head: &str, html: &str| {
+        let raw = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n{}\r\n{}",
+            head, html
+        );
+        let response = Response::from_bytes(raw.as_bytes()).unwrap();
+        let document = response.html_document(&page, None).unwrap();
+        response.subresources(&page, &document).remove(0).2
+    };
+    let referer = |url: &URL| {
+        let request = url.request_text();
+        let line = request.lines().find(|line| line.starts_with("Referer: "))?;
+        Some(line.trim_start_matches("Referer: ").to_string())
+    };
+
+    // The default policy sends the full URL to the same origin and only the origin elsewhere.
+    let image = subresource("", "<img src=/a.png>");
+    assert_eq!(
+        referer(&image).as_deref(),
+        Some("https://example.com/private/page?id=1")
+    );
+    let image = subresource("", "<img src=https://cdn.example.org/a.png>");
+    assert_eq!(referer(&image).as_deref(), Some("https://example.com/"));
+
+    // The header sets the policy, and a <meta> overrides it.
+    let image = subresource("Referrer-Policy: no-referrer\r\n", "<img src=/a.png>");
+    assert_eq!(referer(&image), None);
+    let image = subresource(
+        "Referrer-Policy: no-referrer\r\n",
+        "<meta name=referrer content=origin><img src=/a.png>",
+    );
+    assert_eq!(referer(&image).as_deref(), Some("https://example.com/"));
+
+    // Redirects keep the page as the referrer, but never send it over plain HTTP.
+    let image = subresource("", "<img src=/a.png>");
+    let moved = image.redirect_to("/b.png").unwrap();
+    assert_eq!(
+        referer(&moved).as_deref(),
+        Some("https://example.com/private/page?id=1")
+    );
+    let downgraded = image.redirect_to("http://other.org/b.png").unwrap();
+    assert_eq!(referer(&downgraded), None);
+}
+
+#[test]
+fn test_unread_bodies_log() {
+    // The entries are made by hand, turning the log on would record every other test's requests.
+    let drop_unread = |url: &str, head: &str| {
+        let raw = format!("{}\r\nContent-Length: 5\r\n\r\nhello", head);
+        let stream = Box::new(Cursor::new(raw.into_bytes()));
+        let mut reader = ResponseReader::from_stream(stream, usize::MAX).unwrap();
+        reader.log = Some(netlog::PendingEntry::new("GET", url));
+        drop(reader);
+        netlog::entries()
+            .into_iter()
+            .find(|entry| entry.url == url)
+            .unwrap()
+            .error
+    };
+    // A redirect that gets followed is fine, anything else left unread is an error.
+    let redirect = "HTTP/1.1 301 Moved Permanently\r\nLocation: /b";
+    assert_eq!(drop_unread("http://example.com/unread-a", redirect), None);
+    assert!(drop_unread("http://example.com/unread-b", "HTTP/1.1 200 OK").is_some());
+}
@@ -0,0 +1,497 @@
+/*
+    A WebSocket client, https://www.rfc-editor.org/rfc/rfc6455.
+    The connection starts out as an ordinary HTTP/1.1 GET with `Upgrade: websocket`, over TLS for
+    wss://. Once the server answers 101 Switching Protocols the socket is ours and both sides send
+    frames instead:
+
+     0                   1                   2                   3
+    +-+-+-+-+-------+-+-------------+-------------------------------+
+    |F|R|R|R| opcode|M| Payload len |    Extended payload length    |
+    |I|S|S|S|  (4)  |A|     (7)     |             (16/64)           |
+    |N|V|V|V|       |S|             |                               |
+    +-+-+-+-+-------+-+-------------+ - - - - - - - - - - - - - - - +
+    |  Masking key (client frames only)  |         Payload ...      |
+    +------------------------------------+--------------------------+
+
+    Everything we send is masked, nothing the server sends may be. Messages can be split across
+    several frames (fragmentation), with control frames (ping, pong, close) allowed in between.
+    Pings are answered for you, and a close from the server is echoed back before it's reported.
+*/
+
+use std::io::{BufReader, Read, Write};
+
+use crate::url::{self, Stream, URL};
+
+/// Appended to the key before hashing it, fixed by the RFC.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Close codes from https://www.rfc-editor.org/rfc/rfc6455#section-7.4.1.
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xA,
+}
+
+impl Opcode {
+    fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// The answer to one of our pings.
+    Pong(Vec<u8>),
+    /// The closing handshake is done, with the code and reason the server gave if any.
+    Close(Option<(u16, String)>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Open,
+    /// We've sent a close and are waiting for the server's.
+    Closing,
+    Closed,
+}
+
+struct Frame {
+    fin: bool,
+    opcode: Opcode,
+    payload: Vec<u8>,
+}
+
+pub struct WebSocket {
+    stream: BufReader<Box<dyn Stream>>,
+    state: State,
+    /// The start of a fragmented message, while we wait for the rest of it.
+    fragments: Option<(Opcode, Vec<u8>)>,
+    /// Messages bigger than this fail the connection, it's the URL's body size limit.
+    max_message_size: usize,
+}
+
+impl WebSocket {
+    /// Connect to a ws:// or wss:// URL and do the opening handshake. Any headers on the URL
+    /// (e.g. Origin or Sec-WebSocket-Protocol) are sent along with it.
+    pub fn connect(url: &URL) -> Result<Self, String> {
+        if !url.is_websocket() {
+            return Err(format!("Not a WebSocket URL: {}", url.build()));
+        }
+        let key = base64(&random_bytes::<16>()?);
+        let mut request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n",
+            url.target(),
+            url.host,
+            key
+        );
+        // Unlike a normal GET, the handshake always says which page opened the socket.
+        if let Some(origin) = url.initiator_origin() {
+            request.push_str(&format!("Origin: {}\r\n", origin));
+        }
+        for (name, value) in &url.headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+
+        let mut stream = url.open_stream()?;
+        stream
+            .write_all(request.as_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(|e| format!("Failed to send WebSocket handshake: {}", e))?;
+
+        let mut stream = BufReader::new(stream);
+        let (status, headers) = url::read_head(&mut stream)?;
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(format!("WebSocket handshake failed: {}", status));
+        }
+        let header = |name| url::find_header(&headers, name).unwrap_or_default();
+        if !header("Upgrade").eq_ignore_ascii_case("websocket")
+            || !header("Connection")
+                .split(',')
+                .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        {
+            return Err("WebSocket handshake failed: server didn't upgrade".to_string());
+        }
+        if header("Sec-WebSocket-Accept") != accept_key(&key) {
+            return Err("WebSocket handshake failed: wrong Sec-WebSocket-Accept".to_string());
+        }
+
+        Ok(WebSocket {
+            stream,
+            state: State::Open,
+            fragments: None,
+            max_message_size: url.max_body_size,
+        })
+    }
+
+    pub fn send_text(&mut self, text: &str) -> Result<(), String> {
+        self.send(Opcode::Text, text.as_bytes())
+    }
+
+    /// Start the closing handshake, `read_message` returns `Message::Close` once the server has
+    /// answered.
+    pub fn close(&mut self, code: u16, reason: &str) -> Result<(), String> {
+        if self.state != State::Open {
+            return Ok(());
+        }
+        // Control frames only fit 125 bytes, so cut the reason short (on a char boundary).
+        let mut end = reason.len().min(123);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.state = State::Closing;
+        self.write_frame(Opcode::Close, &payload)
+    }
+
+    /// Wait for the next message.
+    pub fn read_message(&mut self) -> Result<Message, String> {
+        loop {
+            if self.state == State::Closed {
+                return Err("WebSocket is closed".to_string());
+            }
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                Opcode::Ping => {
+                    if self.state == State::Open {
+                        self.write_frame(Opcode::Pong, &frame.payload)?;
+                    }
+                }
+                Opcode::Pong => return Ok(Message::Pong(frame.payload)),
+                Opcode::Close => {
+                    let close = self.parse_close(&frame.payload)?;
+                    if self.state == State::Open {
+                        // Echo the code back to finish the handshake.
+                        let payload = close
+                            .as_ref()
+                            .map_or(Vec::new(), |(code, _)| code.to_be_bytes().to_vec());
+                        self.write_frame(Opcode::Close, &payload)?;
+                    }
+                    self.state = State::Closed;
+                    return Ok(Message::Close(close));
+                }
+                Opcode::Continuation => {
+                    let Some((opcode, mut data)) = self.fragments.take() else {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Unexpected continuation"));
+                    };
+                    data.extend_from_slice(&frame.payload);
+                    if data.len() > self.max_message_size {
+                        return Err(self.fail(CLOSE_TOO_BIG, "Message too big"));
+                    }
+                    if frame.fin {
+                        return self.message(opcode, data);
+                    }
+                    self.fragments = Some((opcode, data));
+                }
+                Opcode::Text | Opcode::Binary => {
+                    if self.fragments.is_some() {
+                        return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Expected a continuation"));
+                    }
+                    if frame.fin {
+                        return self.message(frame.opcode, frame.payload);
+                    }
+                    self.fragments = Some((frame.opcode, frame.payload));
+                }
+            }
+        }
+    }
+
+    fn message(&mut self, opcode: Opcode, data: Vec<u8>) -> Result<Message, String> {
+        if opcode == Opcode::Binary {
+            return Ok(Message::Binary(data));
+        }
+        match String::from_utf8(data) {
+            Ok(text) => Ok(Message::Text(text)),
+            Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "Text message isn't UTF-8")),
+        }
+    }
+
+    fn parse_close(&mut self, payload: &[u8]) -> Result<Option<(u16, String)>, String> {
+        match payload {
+            [] => Ok(None),
+            [_] => Err(self.fail(CLOSE_PROTOCOL_ERROR, "Close frame with half a code")),
+            [high, low, reason @ ..] => match std::str::from_utf8(reason) {
+                Ok(reason) => Ok(Some((
+                    u16::from_be_bytes([*high, *low]),
+                    reason.to_string(),
+                ))),
+                Err(_) => Err(self.fail(CLOSE_INVALID_DATA, "Close reason isn't UTF-8")),
+            },
+        }
+    }
+
+    fn send(&mut self, opcode: Opcode, payload: &[u8]) -> Result<(), String> {
+        if self.state != State::Open {
+            return Err("WebSocket is closed".to_string());
+        }
+        self.write_frame(opcode, payload)
+    }
+
+    fn read_frame(&mut self) -> Result<Frame, String> {
+        let mut head = [0u8; 2];
+        self.read_exact(&mut head)?;
+        let fin = head[0] & 0x80 != 0;
+        if head[0] & 0x70 != 0 {
+            // Reserved bits are only for extensions, and we didn't ask for any.
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Reserved bits set"));
+        }
+        let Some(opcode) = Opcode::from_u8(head[0] & 0x0F) else {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Unknown opcode"));
+        };
+        if head[1] & 0x80 != 0 {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Server frames must not be masked"));
+        }
+
+        let length = match head[1] & 0x7F {
+            126 => {
+                let mut length = [0u8; 2];
+                self.read_exact(&mut length)?;
+                u16::from_be_bytes(length) as u64
+            }
+            127 => {
+                let mut length = [0u8; 8];
+                self.read_exact(&mut length)?;
+                u64::from_be_bytes(length)
+            }
+            length => length as u64,
+        };
+        if opcode.is_control() && (!fin || length > 125) {
+            return Err(self.fail(CLOSE_PROTOCOL_ERROR, "Invalid control frame"));
+        }
+        if length > self.max_message_size as u64 {
+            return Err(self.fail(CLOSE_TOO_BIG, "Message too big"));
+        }
+
+        let mut payload = vec![0; length as usize];
+        self.read_exact(&mut payload)?;
+        Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        })
+    }
+
+    fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), String> {
+        self.stream.read_exact(buffer).map_err(|e| {
+            self.state = State::Closed;
+            format!("WebSocket connection lost: {}", e)
+        })
+    }
+
+    fn write_frame(&mut self, opcode: Opcode, payload: &[u8]) -> Result<(), String> {
+        // We never fragment what we send, so FIN is always set.
+        let mut frame = vec![0x80 | opcode as u8];
+        match payload.len() {
+            length @ 0..=125 => frame.push(0x80 | length as u8),
+            length @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(length as u16).to_be_bytes());
+            }
+            length => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        let mask = random_bytes::<4>()?;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+
+        let stream = self.stream.get_mut();
+        stream
+            .write_all(&frame)
+            .and_then(|_| stream.flush())
+            .map_err(|e| format!("Failed to send WebSocket frame: {}", e))
+    }
+
+    /// Fail the connection: tell the server why (if we still can) and stop using it.
+    fn fail(&mut self, code: u16, reason: &str) -> String {
+        if self.state == State::Open {
+            let _ = self.close(code, reason);
+        }
+        self.state = State::Closed;
+        format!("WebSocket error: {}", reason)
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        let _ = self.close(CLOSE_NORMAL, "");
+    }
+}
+
+/// What the server has to send back in Sec-WebSocket-Accept for our key.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// Bytes for the handshake key and the masks. RFC 6455 section 5.3 needs masks that can't be
+/// predicted, so these come from the OS's random source.
+fn random_bytes<const N: usize>() -> Result<[u8; N], String> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("Failed to get random bytes: {}", e))?;
+    Ok(bytes)
+}
+
+/// SHA-1, https://www.rfc-editor.org/rfc/rfc3174. Only used for the handshake, which is the one
+/// place it's still fine to use.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, h) in digest.chunks_mut(4).zip(h) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[test]
+fn test_websocket() {
+    use std::io::BufRead;
+    use std::net::TcpListener;
+
+    // The example from the RFC.
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+        "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+    );
+    assert_eq!(base64(b"ab"), "YWI=");
+
+    // A server that sends a fragmented message with a ping in the middle, then closes.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = std::io::BufReader::new(&stream);
+        let mut key = String::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+            if let Some(value) = line.trim().strip_prefix("Sec-WebSocket-Key: ") {
+                key = value.to_string();
+            }
+            line.clear();
+        }
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        );
+        (&stream).write_all(response.as_bytes()).unwrap();
+        (&stream)
+            .write_all(b"\x01\x03Hel\x89\x02hi\x80\x02lo\x88\x02\x03\xe8")
+            .unwrap();
+
+        // Read back what the client sent, unmasking it.
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            let mut head = [0u8; 6];
+            reader.read_exact(&mut head).unwrap();
+            assert_eq!(head[1] & 0x80, 0x80, "client frames are masked");
+            let mut payload = vec![0; (head[1] & 0x7F) as usize];
+            reader.read_exact(&mut payload).unwrap();
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= head[2 + i % 4];
+            }
+            frames.push((head[0], payload));
+        }
+        frames
+    });
+
+    let url = URL::from_string(format!("ws://127.0.0.1:{}/chat", port)).unwrap();
+    let mut socket = WebSocket::connect(&url).unwrap();
+    assert_eq!(
+        socket.read_message().unwrap(),
+        Message::Text("Hello".to_string())
+    );
+    assert_eq!(
+        socket.read_message().unwrap(),
+        Message::Close(Some((CLOSE_NORMAL, String::new())))
+    );
+    assert!(socket.send_text("too late").is_err());
+
+    // The ping got its pong, and the close was echoed.
+    let frames = server.join().unwrap();
+    assert_eq!(frames[0], (0x8A, b"hi".to_vec()));
+    assert_eq!(frames[1], (0x88, vec![0x03, 0xe8]));
+}